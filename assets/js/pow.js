@@ -0,0 +1,48 @@
+// Solves the self-hosted proof-of-work CAPTCHA in the browser, see PowCaptcha in src/captcha.rs
+const powCaptcha = (() => {
+    let solution = "";
+
+    async function sha256(text) {
+        const digest = await crypto.subtle.digest("SHA-256", new TextEncoder().encode(text));
+        return Array.from(new Uint8Array(digest), (b) => b.toString(16).padStart(2, "0")).join("");
+    }
+
+    async function solve(widget) {
+        widget.textContent = "Verifying your browser...";
+
+        try {
+            const response = await fetch("/api/captcha/challenge");
+            const challenge = await response.json();
+
+            for (let number = 0; number <= challenge.maxnumber; number++) {
+                if (await sha256(challenge.salt + number) === challenge.challenge) {
+                    solution = JSON.stringify({
+                        challenge: challenge.challenge,
+                        salt: challenge.salt,
+                        number,
+                        signature: challenge.signature
+                    });
+                    widget.textContent = "✅ Verified";
+                    return;
+                }
+            }
+        } catch (error) {
+            console.error("Failed to solve proof-of-work challenge: ", error);
+        }
+
+        widget.textContent = "Verification failed, please reload the page.";
+    }
+
+    function solveAll() {
+        document.querySelectorAll(".pow-captcha").forEach(solve);
+    }
+
+    // Loaded asynchronously, so the page may already be parsed
+    if (document.readyState === "loading") {
+        document.addEventListener("DOMContentLoaded", solveAll);
+    } else {
+        solveAll();
+    }
+
+    return { getResponse: () => solution };
+})();
@@ -4,14 +4,11 @@ function getCookie(name) {
 }
 
 function getToken() {
+    // Older versions kept the login token in a cookie, it is exchanged for a session once and then cleared
     const token = new URLSearchParams(window.location.search).get("token");
     return token ? { token, source: "params" } : { token: getCookie("token"), source: "cookie" };
 }
 
-function setToken(token) {
-    document.cookie = `token=${token}; path=/; Secure; HttpOnly`;
-}
-
 function isValidEmail(email) {
     return /^[^\s@]+@[^\s@]+\.[^\s@]+$/.test(email);
 }
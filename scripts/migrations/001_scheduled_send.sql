@@ -0,0 +1,8 @@
+-- Scheduled send: sender-chosen earliest delivery time
+-- Usage: psql -U your_username -d your_database_name -f 001_scheduled_send.sql
+
+ALTER TABLE messages ADD COLUMN deliver_after TIMESTAMPTZ;
+
+ALTER TABLE messages DROP CONSTRAINT messages_status_check;
+ALTER TABLE messages ADD CONSTRAINT messages_status_check
+    CHECK (status IN ('scheduled', 'pending', 'sending', 'sent', 'failed'));
@@ -0,0 +1,8 @@
+-- Message expiry / time-to-live
+-- Usage: psql -U your_username -d your_database_name -f 002_message_expiry.sql
+
+ALTER TABLE messages ADD COLUMN expires_at TIMESTAMPTZ;
+
+ALTER TABLE messages DROP CONSTRAINT messages_status_check;
+ALTER TABLE messages ADD CONSTRAINT messages_status_check
+    CHECK (status IN ('scheduled', 'pending', 'sending', 'sent', 'failed', 'expired'));
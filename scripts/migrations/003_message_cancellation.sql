@@ -0,0 +1,6 @@
+-- Sender-side cancellation of pending messages
+-- Usage: psql -U your_username -d your_database_name -f 003_message_cancellation.sql
+
+ALTER TABLE messages DROP CONSTRAINT messages_status_check;
+ALTER TABLE messages ADD CONSTRAINT messages_status_check
+    CHECK (status IN ('scheduled', 'pending', 'sending', 'sent', 'failed', 'expired', 'cancelled'));
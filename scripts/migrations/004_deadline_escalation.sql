@@ -0,0 +1,12 @@
+-- Deadline-based automatic priority escalation
+-- Usage: psql -U your_username -d your_database_name -f 004_deadline_escalation.sql
+
+ALTER TABLE messages ADD COLUMN needed_by TIMESTAMPTZ;
+
+CREATE TABLE delivery_log (
+    id SERIAL PRIMARY KEY,
+    message_id INTEGER NOT NULL REFERENCES messages(id),
+    logged_time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    event TEXT NOT NULL,
+    detail TEXT NOT NULL
+);
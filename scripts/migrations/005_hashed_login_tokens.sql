@@ -0,0 +1,15 @@
+-- Store login tokens hashed at rest
+-- Usage: psql -U your_username -d your_database_name -f 005_hashed_login_tokens.sql
+-- Existing login links keep working, as their tokens are hashed in place
+
+CREATE TABLE login_tokens (
+    id SERIAL PRIMARY KEY,
+    user_uid INTEGER NOT NULL REFERENCES users(uid) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO login_tokens (user_uid, token_hash, created_time)
+    SELECT uid, encode(sha256(convert_to(token, 'UTF8')), 'hex'), added_time FROM users;
+
+ALTER TABLE users DROP COLUMN token;
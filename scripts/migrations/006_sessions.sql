@@ -0,0 +1,10 @@
+-- Server-side sessions instead of the permanent token in a cookie
+-- Usage: psql -U your_username -d your_database_name -f 006_sessions.sql
+
+CREATE TABLE sessions (
+    id SERIAL PRIMARY KEY,
+    user_uid INTEGER NOT NULL REFERENCES users(uid) ON DELETE CASCADE,
+    session_hash TEXT NOT NULL UNIQUE,
+    created_time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_time TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Active device/session management for users
+-- Usage: psql -U your_username -d your_database_name -f 007_session_devices.sql
+
+ALTER TABLE sessions ADD COLUMN last_seen_time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE sessions ADD COLUMN user_agent TEXT NOT NULL DEFAULT '';
+ALTER TABLE sessions ADD COLUMN ip TEXT NOT NULL DEFAULT '';
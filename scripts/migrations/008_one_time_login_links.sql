@@ -0,0 +1,5 @@
+-- One-time, expiring magic login links
+-- Usage: psql -U your_username -d your_database_name -f 008_one_time_login_links.sql
+
+ALTER TABLE login_tokens ADD COLUMN single_use BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE login_tokens ADD COLUMN expires_time TIMESTAMPTZ;
@@ -0,0 +1,12 @@
+-- Passkey / WebAuthn login for returning users
+-- Usage: psql -U your_username -d your_database_name -f 009_passkeys.sql
+
+CREATE TABLE passkeys (
+    id SERIAL PRIMARY KEY,
+    user_uid INTEGER NOT NULL REFERENCES users(uid) ON DELETE CASCADE,
+    credential_id TEXT NOT NULL UNIQUE,
+    passkey TEXT NOT NULL,
+    name TEXT NOT NULL,
+    created_time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_time TIMESTAMPTZ
+);
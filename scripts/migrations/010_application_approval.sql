@@ -0,0 +1,6 @@
+-- Owner approval of new account applications
+-- Usage: psql -U your_username -d your_database_name -f 010_application_approval.sql
+
+-- Existing users stay active, only new applications start out pending
+ALTER TABLE users
+    ADD COLUMN status TEXT NOT NULL DEFAULT 'active' CONSTRAINT users_status_check CHECK (status IN ('pending_approval', 'active', 'rejected'));
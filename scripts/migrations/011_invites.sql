@@ -0,0 +1,12 @@
+-- Owner-issued invite links with preset roles
+-- Usage: psql -U your_username -d your_database_name -f 011_invites.sql
+
+CREATE TABLE invites (
+    id SERIAL PRIMARY KEY,
+    code_hash TEXT NOT NULL UNIQUE,
+    role INTEGER NOT NULL,
+    max_uses INTEGER,
+    uses INTEGER NOT NULL DEFAULT 0,
+    expires_time TIMESTAMPTZ,
+    created_time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
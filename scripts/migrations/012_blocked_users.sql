@@ -0,0 +1,6 @@
+-- Blocking users from the admin API and CLI
+-- Usage: psql -U your_username -d your_database_name -f 012_blocked_users.sql
+
+ALTER TABLE users
+    DROP CONSTRAINT users_status_check,
+    ADD CONSTRAINT users_status_check CHECK (status IN ('pending_approval', 'active', 'rejected', 'blocked'));
@@ -0,0 +1,28 @@
+-- Capability-based roles
+-- Usage: psql -U your_username -d your_database_name -f 013_roles.sql
+
+CREATE TABLE roles (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    allowed_priorities TEXT[] NOT NULL DEFAULT '{standard,urgent,immediate}',
+    daily_quota INTEGER,
+    badge_colour TEXT NOT NULL DEFAULT 'blue' CONSTRAINT roles_badge_colour_check CHECK (badge_colour IN ('gray', 'gold', 'blue')),
+    bypass_sleep BOOLEAN NOT NULL DEFAULT false,
+    admin BOOLEAN NOT NULL DEFAULT false
+);
+
+-- Roles 0 and 1 keep their gray and gold ticks
+INSERT INTO roles (id, name, badge_colour, bypass_sleep, admin) VALUES
+    (0, 'verified', 'gray', false, false),
+    (1, 'trusted', 'gold', false, false);
+
+-- Any other roles in use, including 2, keep their blue tick without extra capabilities.
+--    Admin access is granted separately with: enviame admin owner <uid>
+INSERT INTO roles (id, name)
+    SELECT DISTINCT role, 'role_' || role FROM users WHERE role NOT IN (SELECT id FROM roles)
+    UNION
+    SELECT DISTINCT role, 'role_' || role FROM invites WHERE role NOT IN (SELECT id FROM roles);
+
+ALTER TABLE users ADD CONSTRAINT users_role_fkey FOREIGN KEY (role) REFERENCES roles(id);
+
+ALTER TABLE invites ADD CONSTRAINT invites_role_fkey FOREIGN KEY (role) REFERENCES roles(id);
@@ -0,0 +1,8 @@
+-- Per-user allowed priorities, overriding those of the user's role
+-- Usage: psql -U your_username -d your_database_name -f 014_user_allowed_priorities.sql
+
+ALTER TABLE roles
+    ADD CONSTRAINT roles_allowed_priorities_check CHECK (allowed_priorities <@ '{standard,urgent,immediate}');
+
+ALTER TABLE users
+    ADD COLUMN allowed_priorities TEXT[] CONSTRAINT users_allowed_priorities_check CHECK (allowed_priorities <@ '{standard,urgent,immediate}');
@@ -0,0 +1,14 @@
+-- Weekly priority budgets per role
+-- Usage: psql -U your_username -d your_database_name -f 015_priority_budgets.sql
+
+ALTER TABLE roles
+    ADD COLUMN weekly_urgent_budget INTEGER,
+    ADD COLUMN weekly_immediate_budget INTEGER,
+    ADD COLUMN downgrade_over_budget BOOLEAN NOT NULL DEFAULT false;
+
+-- Priority chosen by the sender, unaffected by deadline escalation. Existing messages use their current priority
+ALTER TABLE messages ADD COLUMN submitted_priority TEXT;
+
+UPDATE messages SET submitted_priority = priority;
+
+ALTER TABLE messages ALTER COLUMN submitted_priority SET NOT NULL;
@@ -0,0 +1,13 @@
+-- Guest messages confirmed with an emailed code
+-- Usage: psql -U your_username -d your_database_name -f 016_guest_messages.sql
+
+ALTER TABLE messages DROP CONSTRAINT messages_status_check;
+ALTER TABLE messages ADD CONSTRAINT messages_status_check
+    CHECK (status IN ('unconfirmed', 'scheduled', 'pending', 'sending', 'sent', 'failed', 'expired', 'cancelled'));
+
+CREATE TABLE guest_verifications (
+    message_id INTEGER PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    expires_time TIMESTAMPTZ NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0
+);
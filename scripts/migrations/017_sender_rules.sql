@@ -0,0 +1,12 @@
+-- Sender blocklist and allowlist
+-- Usage: psql -U your_username -d your_database_name -f 017_sender_rules.sql
+
+CREATE TABLE sender_rules (
+    id SERIAL PRIMARY KEY,
+    kind TEXT NOT NULL CHECK (kind IN ('uid', 'email', 'domain', 'ip')),
+    value TEXT NOT NULL,
+    action TEXT NOT NULL DEFAULT 'block' CHECK (action IN ('block', 'allow')),
+    note TEXT NOT NULL DEFAULT '',
+    created_time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (kind, value)
+);
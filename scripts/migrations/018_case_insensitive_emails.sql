@@ -0,0 +1,30 @@
+-- Case-insensitive email identity
+-- Usage: psql -U your_username -d your_database_name -f 018_case_insensitive_emails.sql
+
+BEGIN;
+
+-- Users sharing an address apart from case or surrounding spaces are listed, and nothing is changed
+--    until the duplicates are merged or deleted
+DO $$
+DECLARE
+    duplicate RECORD;
+    has_duplicates BOOLEAN := false;
+BEGIN
+    FOR duplicate IN
+        SELECT lower(trim(email)) AS email, array_agg(uid ORDER BY uid) AS uids
+        FROM users GROUP BY 1 HAVING count(*) > 1
+    LOOP
+        RAISE WARNING 'Users % share the email %', duplicate.uids, duplicate.email;
+        has_duplicates := true;
+    END LOOP;
+
+    IF has_duplicates THEN
+        RAISE EXCEPTION 'Merge or delete the duplicate users listed above, then run this script again';
+    END IF;
+END $$;
+
+UPDATE users SET email = lower(trim(email)) WHERE email <> lower(trim(email));
+ALTER TABLE users DROP CONSTRAINT users_email_key;
+CREATE UNIQUE INDEX users_email_lower_key ON users (lower(email));
+
+COMMIT;
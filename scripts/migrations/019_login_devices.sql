@@ -0,0 +1,9 @@
+-- Browser and OS families users have logged in with, used for new device alerts
+-- Usage: psql -U your_username -d your_database_name -f 019_login_devices.sql
+
+CREATE TABLE login_devices (
+    user_uid INTEGER NOT NULL REFERENCES users(uid) ON DELETE CASCADE,
+    device TEXT NOT NULL,
+    last_login_time TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_uid, device)
+);
@@ -0,0 +1,141 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+// User management shared by the admin API and the `enviame admin` command
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::constants::CALENDAR_DATETIME_FORMAT;
+use crate::utils::escape_like;
+
+#[derive(Serialize)]
+pub struct AdminUser {
+    pub uid: i32,
+    pub added_time: String,
+    pub name: String,
+    pub email: String,
+    pub verified: bool,
+    pub role: i32,
+    pub status: String,
+    pub allowed_priorities: Option<Vec<String>>,
+}
+
+// Lists users whose name or email contains the search string, all users if it is empty
+pub async fn list_users(db: &PgPool, search: &str) -> sqlx::Result<Vec<AdminUser>> {
+    let users = sqlx::query!(
+        "SELECT uid, added_time, name, email, verified, role, status, allowed_priorities FROM users WHERE name ILIKE '%' || $1 || '%' ESCAPE '\\' OR email ILIKE '%' || $1 || '%' ESCAPE '\\' ORDER BY uid",
+        escape_like(search)
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|rec| AdminUser {
+        uid: rec.uid,
+        added_time: rec.added_time.format(CALENDAR_DATETIME_FORMAT).to_string(),
+        name: rec.name,
+        email: rec.email,
+        verified: rec.verified,
+        role: rec.role,
+        status: rec.status,
+        allowed_priorities: rec.allowed_priorities,
+    })
+    .collect();
+
+    Ok(users)
+}
+
+// The functions below return whether a matching user was found
+
+// Unknown roles are treated like unknown users
+pub async fn set_user_role(db: &PgPool, uid: i32, role: i32) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE users SET role = $1 WHERE uid = $2 AND EXISTS (SELECT 1 FROM roles WHERE id = $1)",
+        role,
+        uid
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Admin access is only ever granted here or by editing the roles table. The owner role is created on first use
+pub async fn set_user_owner(db: &PgPool, uid: i32) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "WITH owner AS (INSERT INTO roles (id, name, badge_colour, bypass_sleep, admin) SELECT COALESCE(MAX(id), 0) + 1, 'owner', 'blue', true, true FROM roles HAVING EXISTS (SELECT 1 FROM users WHERE uid = $1) ON CONFLICT (name) DO UPDATE SET bypass_sleep = true, admin = true RETURNING id) UPDATE users SET role = owner.id FROM owner WHERE uid = $1",
+        uid
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Without priorities, the user can use the priorities allowed by their role again
+pub async fn set_user_priorities(
+    db: &PgPool,
+    uid: i32,
+    priorities: Option<&[String]>,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE users SET allowed_priorities = $1 WHERE uid = $2",
+        priorities,
+        uid
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Blocked users are signed out everywhere and cannot log in again until unblocked
+pub async fn set_user_blocked(db: &PgPool, uid: i32, blocked: bool) -> sqlx::Result<bool> {
+    let result = if blocked {
+        sqlx::query!("UPDATE users SET status = 'blocked' WHERE uid = $1", uid)
+            .execute(db)
+            .await?
+    } else {
+        sqlx::query!(
+            "UPDATE users SET status = 'active' WHERE uid = $1 AND status = 'blocked'",
+            uid
+        )
+        .execute(db)
+        .await?
+    };
+
+    if blocked {
+        sqlx::query!("DELETE FROM login_tokens WHERE user_uid = $1", uid)
+            .execute(db)
+            .await?;
+
+        sqlx::query!("DELETE FROM sessions WHERE user_uid = $1", uid)
+            .execute(db)
+            .await?;
+    }
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Messages from deleted users are kept, but no longer linked to an account
+pub async fn delete_user(db: &PgPool, uid: i32) -> sqlx::Result<bool> {
+    let deleted = sqlx::query!(
+        "WITH detached AS (UPDATE messages SET user_uid = NULL WHERE user_uid = $1) DELETE FROM users WHERE uid = $1 RETURNING uid",
+        uid
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(deleted.is_some())
+}
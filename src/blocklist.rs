@@ -0,0 +1,285 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Sender rules shared by the submission, application and login link handlers, the admin API and the `enviame admin` command
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+use std::net::IpAddr;
+
+use crate::constants::{CALENDAR_DATETIME_FORMAT, HOMEPAGE_URL, MID_HASH_KEY};
+use crate::utils::{IpRange, generate_hash};
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    Uid,
+    Email,
+    Domain,
+    Ip,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Block,
+    Allow,
+}
+
+impl RuleKind {
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "uid" => Some(RuleKind::Uid),
+            "email" => Some(RuleKind::Email),
+            "domain" => Some(RuleKind::Domain),
+            "ip" => Some(RuleKind::Ip),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RuleKind::Uid => "uid",
+            RuleKind::Email => "email",
+            RuleKind::Domain => "domain",
+            RuleKind::Ip => "ip",
+        }
+    }
+
+    // Values are stored in the form they are matched in, None if the value is invalid for this kind
+    pub fn normalise(self, value: &str) -> Option<String> {
+        let value = value.trim();
+        match self {
+            RuleKind::Uid => value.parse::<i32>().ok().map(|uid| uid.to_string()),
+            RuleKind::Email => value.contains('@').then(|| value.to_lowercase()),
+            RuleKind::Domain => {
+                let domain = value.trim_start_matches('@').to_lowercase();
+                (!domain.is_empty() && !domain.contains('@')).then_some(domain)
+            }
+            RuleKind::Ip => IpRange::parse(value).map(|range| range.to_string()),
+        }
+    }
+}
+
+impl RuleAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "block" => Some(RuleAction::Block),
+            "allow" => Some(RuleAction::Allow),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RuleAction::Block => "block",
+            RuleAction::Allow => "allow",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SenderRule {
+    pub id: i32,
+    pub kind: String,
+    pub value: String,
+    pub action: String,
+    pub note: String,
+    pub created_time: String,
+}
+
+// A sender is blocked if their account is blocked, or if any block rule matches them and no allow rule does,
+//    so an allowed address can still write from a blocked domain or IP range. Domain rules also cover subdomains.
+//    Senders without a session are matched to the account with their email, so blocked users cannot write as guests
+pub async fn is_sender_blocked(
+    db: impl PgExecutor<'_>,
+    uid: Option<i32>,
+    email: &str,
+    ip: &str,
+) -> bool {
+    // Unparsable addresses are not matched against IP rules rather than failing the query
+    let ip = ip.parse::<IpAddr>().ok().map(|ip| ip.to_string());
+
+    sqlx::query!(
+        "WITH sender AS (SELECT uid, status FROM users WHERE uid = $1 OR ($1 IS NULL AND lower(email) = lower($2))) SELECT EXISTS (SELECT 1 FROM sender WHERE status = 'blocked') OR COALESCE((SELECT bool_or(action = 'block') AND NOT bool_or(action = 'allow') FROM sender_rules WHERE (kind = 'uid' AND value IN (SELECT uid::text FROM sender)) OR (kind = 'email' AND value = lower($2)) OR (kind = 'domain' AND (value = lower(split_part($2, '@', 2)) OR right(lower(split_part($2, '@', 2)), length(value) + 1) = '.' || value)) OR CASE WHEN kind = 'ip' THEN $3::text::inet <<= value::inet ELSE false END), false) AS \"blocked!\"",
+        uid,
+        email.trim(),
+        ip
+    )
+    .fetch_one(db)
+    .await
+    .unwrap()
+    .blocked
+}
+
+pub async fn list_rules(db: &PgPool) -> sqlx::Result<Vec<SenderRule>> {
+    let rules = sqlx::query!(
+        "SELECT id, kind, value, action, note, created_time FROM sender_rules ORDER BY id"
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|rec| SenderRule {
+        id: rec.id,
+        kind: rec.kind,
+        value: rec.value,
+        action: rec.action,
+        note: rec.note,
+        created_time: rec
+            .created_time
+            .format(CALENDAR_DATETIME_FORMAT)
+            .to_string(),
+    })
+    .collect();
+
+    Ok(rules)
+}
+
+// Adding a rule for a value that already has one replaces its action and note. Returns the rule id
+pub async fn add_rule(
+    db: &PgPool,
+    kind: RuleKind,
+    value: &str,
+    action: RuleAction,
+    note: &str,
+) -> sqlx::Result<i32> {
+    let rec = sqlx::query!(
+        "INSERT INTO sender_rules (kind, value, action, note) VALUES ($1, $2, $3, $4) ON CONFLICT (kind, value) DO UPDATE SET action = EXCLUDED.action, note = EXCLUDED.note RETURNING id",
+        kind.as_str(),
+        value,
+        action.as_str(),
+        note
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(rec.id)
+}
+
+// Returns whether a matching rule was found
+pub async fn delete_rule(db: &PgPool, id: i32) -> sqlx::Result<bool> {
+    let result = sqlx::query!("DELETE FROM sender_rules WHERE id = $1", id)
+        .execute(db)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Signed link in notification emails that blocks the sender of a message
+pub fn block_sender_link(mid: i32) -> String {
+    let hash = generate_hash(&format!("block:{mid}"), &MID_HASH_KEY);
+    format!("{}block?mid={mid}&hash={hash}", *HOMEPAGE_URL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalise_uid() {
+        assert_eq!(RuleKind::Uid.normalise(" 42 "), Some("42".to_owned()));
+        assert_eq!(RuleKind::Uid.normalise("042"), Some("42".to_owned()));
+        assert_eq!(RuleKind::Uid.normalise("alice"), None);
+        assert_eq!(RuleKind::Uid.normalise(""), None);
+    }
+
+    #[test]
+    fn normalise_email() {
+        assert_eq!(
+            RuleKind::Email.normalise(" Alice@Example.COM "),
+            Some("alice@example.com".to_owned())
+        );
+        assert_eq!(RuleKind::Email.normalise("example.com"), None);
+    }
+
+    #[test]
+    fn normalise_domain() {
+        assert_eq!(
+            RuleKind::Domain.normalise("Example.COM"),
+            Some("example.com".to_owned())
+        );
+        assert_eq!(
+            RuleKind::Domain.normalise(" @example.com "),
+            Some("example.com".to_owned())
+        );
+        assert_eq!(RuleKind::Domain.normalise("@"), None);
+        assert_eq!(RuleKind::Domain.normalise("alice@example.com"), None);
+    }
+
+    #[test]
+    fn normalise_ip() {
+        assert_eq!(
+            RuleKind::Ip.normalise("203.0.113.7"),
+            Some("203.0.113.7/32".to_owned())
+        );
+        assert_eq!(
+            RuleKind::Ip.normalise(" 10.0.0.0/8 "),
+            Some("10.0.0.0/8".to_owned())
+        );
+        assert_eq!(
+            RuleKind::Ip.normalise("2001:DB8::/32"),
+            Some("2001:db8::/32".to_owned())
+        );
+        assert_eq!(RuleKind::Ip.normalise("10.0.0.0/33"), None);
+        assert_eq!(RuleKind::Ip.normalise("example.com"), None);
+    }
+
+    // Needs the schema in the database at DATABASE_URL and is skipped without it, its changes are rolled back
+    #[tokio::test]
+    async fn blocked_users_sending_as_guests() {
+        let Ok(url) = std::env::var("DATABASE_URL") else {
+            return;
+        };
+        let db = PgPool::connect(&url).await.unwrap();
+        let mut tx = db.begin().await.unwrap();
+        let ip = "203.0.113.7";
+
+        let uid = sqlx::query_scalar!(
+            "INSERT INTO users (name, email, verified, role) VALUES ('Blocked', 'blocked.sender@example.com', true, 0) RETURNING uid"
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        assert!(!is_sender_blocked(&mut *tx, None, "blocked.sender@example.com", ip).await);
+
+        // A uid rule also stops the user writing as a guest with their email
+        sqlx::query!(
+            "INSERT INTO sender_rules (kind, value, action, note) VALUES ('uid', $1, 'block', '')",
+            uid.to_string()
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        assert!(is_sender_blocked(&mut *tx, Some(uid), "blocked.sender@example.com", ip).await);
+        assert!(is_sender_blocked(&mut *tx, None, " Blocked.Sender@Example.com ", ip).await);
+        assert!(!is_sender_blocked(&mut *tx, None, "someone.else@example.com", ip).await);
+
+        // So does blocking the account itself
+        sqlx::query!(
+            "DELETE FROM sender_rules WHERE kind = 'uid' AND value = $1",
+            uid.to_string()
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        assert!(!is_sender_blocked(&mut *tx, None, "blocked.sender@example.com", ip).await);
+        sqlx::query!("UPDATE users SET status = 'blocked' WHERE uid = $1", uid)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        assert!(is_sender_blocked(&mut *tx, None, "blocked.sender@example.com", ip).await);
+
+        tx.rollback().await.unwrap();
+    }
+}
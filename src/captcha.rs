@@ -0,0 +1,424 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use rand::RngExt;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use crate::constants::{
+    CAPTCHA_POW_DIFFICULTY, CAPTCHA_PROVIDER, CAPTCHA_SECRET_KEY, CAPTCHA_SITE_KEY, DEPLOY_ENV,
+    MID_HASH_KEY, POW_CHALLENGE_LIFETIME,
+};
+use crate::utils::{check_hash, generate_hash, generate_random_token};
+
+pub type VerifyFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send + 'a>>;
+
+// Verifies the CAPTCHA responses submitted with account applications and login link requests
+pub trait CaptchaVerifier: Send + Sync {
+    // Widget shown on the forms, None if there is nothing to solve
+    fn widget(&self) -> Option<CaptchaWidget>;
+
+    // Whether the response is valid. Errors only if the provider could not be reached
+    fn verify<'a>(&'a self, response: &'a str, remote_ip: &'a str) -> VerifyFuture<'a>;
+
+    // Challenge for the widget to solve, only issued by self-hosted providers
+    fn challenge(&self) -> Option<PowChallenge> {
+        None
+    }
+}
+
+// Rendered by captcha.html
+pub struct CaptchaWidget {
+    pub name: &'static str,
+    pub script: &'static str,
+    pub class: &'static str,
+    // Global object of the provider's script, whose getResponse() returns the response
+    pub global: &'static str,
+    pub site_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+// reCAPTCHA, hCaptcha and Turnstile share the same siteverify API, only their endpoints and scripts differ
+pub struct SiteVerifyCaptcha {
+    name: &'static str,
+    script: &'static str,
+    class: &'static str,
+    global: &'static str,
+    verify_url: &'static str,
+    site_key: String,
+    secret_key: String,
+}
+
+impl SiteVerifyCaptcha {
+    pub fn recaptcha(site_key: String, secret_key: String) -> Self {
+        SiteVerifyCaptcha {
+            name: "reCAPTCHA",
+            script: "https://www.google.com/recaptcha/api.js",
+            class: "g-recaptcha",
+            global: "grecaptcha",
+            verify_url: "https://www.google.com/recaptcha/api/siteverify",
+            site_key,
+            secret_key,
+        }
+    }
+
+    pub fn hcaptcha(site_key: String, secret_key: String) -> Self {
+        SiteVerifyCaptcha {
+            name: "hCaptcha",
+            script: "https://js.hcaptcha.com/1/api.js",
+            class: "h-captcha",
+            global: "hcaptcha",
+            verify_url: "https://api.hcaptcha.com/siteverify",
+            site_key,
+            secret_key,
+        }
+    }
+
+    pub fn turnstile(site_key: String, secret_key: String) -> Self {
+        SiteVerifyCaptcha {
+            name: "Turnstile",
+            script: "https://challenges.cloudflare.com/turnstile/v0/api.js",
+            class: "cf-turnstile",
+            global: "turnstile",
+            verify_url: "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            site_key,
+            secret_key,
+        }
+    }
+}
+
+impl CaptchaVerifier for SiteVerifyCaptcha {
+    fn widget(&self) -> Option<CaptchaWidget> {
+        Some(CaptchaWidget {
+            name: self.name,
+            script: self.script,
+            class: self.class,
+            global: self.global,
+            site_key: Some(self.site_key.clone()),
+        })
+    }
+
+    fn verify<'a>(&'a self, response: &'a str, remote_ip: &'a str) -> VerifyFuture<'a> {
+        Box::pin(async move {
+            if response.is_empty() {
+                return Ok(false);
+            }
+
+            let params = [
+                ("secret", self.secret_key.as_str()),
+                ("response", response),
+                ("remoteip", remote_ip),
+            ];
+            let result = Client::new()
+                .post(self.verify_url)
+                .form(&params)
+                .send()
+                .await?
+                .json::<SiteVerifyResponse>()
+                .await;
+
+            // Malformed replies are treated as failed verifications
+            Ok(result.is_ok_and(|r| r.success))
+        })
+    }
+}
+
+// Challenge of the self-hosted proof-of-work CAPTCHA, in the format used by ALTCHA. The widget searches
+//    for the number up to maxnumber whose SHA-256 digest, appended to the salt, is the challenge
+#[derive(Serialize)]
+pub struct PowChallenge {
+    algorithm: &'static str,
+    challenge: String,
+    maxnumber: u64,
+    salt: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct PowSolution {
+    challenge: String,
+    salt: String,
+    number: u64,
+    signature: String,
+}
+
+fn pow_digest(salt: &str, number: u64) -> String {
+    hex::encode(Sha256::digest(format!("{salt}{number}").as_bytes()))
+}
+
+// Verified without any third-party request. Challenges are signed with HASH_KEY, so none need to be stored until solved
+pub struct PowCaptcha {
+    max_number: u64,
+    key: String,
+    // Signatures of solved challenges until they expire, so that each challenge is only accepted once
+    used: Mutex<HashMap<String, i64>>,
+}
+
+impl PowCaptcha {
+    pub fn new(max_number: u64, key: String) -> Self {
+        PowCaptcha {
+            max_number,
+            key,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl CaptchaVerifier for PowCaptcha {
+    fn widget(&self) -> Option<CaptchaWidget> {
+        Some(CaptchaWidget {
+            name: "Proof-of-work",
+            script: "/assets/js/pow.js",
+            class: "pow-captcha",
+            global: "powCaptcha",
+            site_key: None,
+        })
+    }
+
+    fn verify<'a>(&'a self, response: &'a str, _remote_ip: &'a str) -> VerifyFuture<'a> {
+        Box::pin(async move {
+            let Ok(solution) = serde_json::from_str::<PowSolution>(response) else {
+                return Ok(false);
+            };
+
+            // The expiry is part of the salt, so it is covered by the signed challenge
+            let now = Utc::now().timestamp();
+            let Some(expires) = solution
+                .salt
+                .rsplit_once("?expires=")
+                .and_then(|(_, expires)| expires.parse::<i64>().ok())
+                .filter(|expires| *expires > now)
+            else {
+                return Ok(false);
+            };
+
+            if !check_hash(
+                &format!("pow:{}", solution.challenge),
+                &solution.signature,
+                &self.key,
+            ) || pow_digest(&solution.salt, solution.number) != solution.challenge
+            {
+                return Ok(false);
+            }
+
+            let mut used = self.used.lock().unwrap();
+            used.retain(|_, expires| *expires > now);
+
+            Ok(used.insert(solution.signature, expires).is_none())
+        })
+    }
+
+    fn challenge(&self) -> Option<PowChallenge> {
+        let expires = (Utc::now() + POW_CHALLENGE_LIFETIME).timestamp();
+        let salt = format!("{}?expires={expires}", generate_random_token());
+        let number = rand::rng().random_range(0..=self.max_number);
+        let challenge = pow_digest(&salt, number);
+        let signature = generate_hash(&format!("pow:{challenge}"), &self.key);
+
+        Some(PowChallenge {
+            algorithm: "SHA-256",
+            challenge,
+            maxnumber: self.max_number,
+            salt,
+            signature,
+        })
+    }
+}
+
+// Accepts every request, so that beta and development builds can go through the full forms
+pub struct NoCaptcha;
+
+impl CaptchaVerifier for NoCaptcha {
+    fn widget(&self) -> Option<CaptchaWidget> {
+        None
+    }
+
+    fn verify<'a>(&'a self, _response: &'a str, _remote_ip: &'a str) -> VerifyFuture<'a> {
+        Box::pin(async { Ok(true) })
+    }
+}
+
+// Builds the verifier selected by CAPTCHA_PROVIDER
+pub fn captcha_from_config() -> Arc<dyn CaptchaVerifier> {
+    let keys = || (CAPTCHA_SITE_KEY.clone(), CAPTCHA_SECRET_KEY.clone());
+
+    match CAPTCHA_PROVIDER.as_str() {
+        "recaptcha" => {
+            let (site_key, secret_key) = keys();
+            Arc::new(SiteVerifyCaptcha::recaptcha(site_key, secret_key))
+        }
+        "hcaptcha" => {
+            let (site_key, secret_key) = keys();
+            Arc::new(SiteVerifyCaptcha::hcaptcha(site_key, secret_key))
+        }
+        "turnstile" => {
+            let (site_key, secret_key) = keys();
+            Arc::new(SiteVerifyCaptcha::turnstile(site_key, secret_key))
+        }
+        "pow" => Arc::new(PowCaptcha::new(
+            *CAPTCHA_POW_DIFFICULTY,
+            MID_HASH_KEY.clone(),
+        )),
+        "none" => {
+            assert!(
+                *DEPLOY_ENV != "prod",
+                "CAPTCHA_PROVIDER cannot be none in prod"
+            );
+            Arc::new(NoCaptcha)
+        }
+        provider => panic!("Unknown CAPTCHA_PROVIDER {provider}"),
+    }
+}
+
+// Shared by the handlers of forms with a CAPTCHA, returning the response to send if verification fails
+pub async fn verify_captcha(
+    verifier: &dyn CaptchaVerifier,
+    response: &str,
+    remote_ip: &str,
+) -> Result<(), Response> {
+    match verifier.verify(response, remote_ip).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::BAD_REQUEST, "CAPTCHA verification failed").into_response()),
+        Err(_) => {
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Error verifying CAPTCHA").into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captcha() -> PowCaptcha {
+        PowCaptcha::new(1000, "test key".to_owned())
+    }
+
+    // Finds the number the way the widget does
+    fn solve(challenge: &PowChallenge) -> serde_json::Value {
+        let number = (0..=challenge.maxnumber)
+            .find(|n| pow_digest(&challenge.salt, *n) == challenge.challenge)
+            .unwrap();
+
+        serde_json::json!({
+            "algorithm": challenge.algorithm,
+            "challenge": challenge.challenge,
+            "number": number,
+            "salt": challenge.salt,
+            "signature": challenge.signature,
+        })
+    }
+
+    async fn verify(captcha: &PowCaptcha, solution: &serde_json::Value) -> bool {
+        captcha
+            .verify(&solution.to_string(), "203.0.113.7")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_solutions_once() {
+        let captcha = captcha();
+        let solution = solve(&captcha.challenge().unwrap());
+
+        assert!(verify(&captcha, &solution).await);
+        assert!(!verify(&captcha, &solution).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_numbers() {
+        let captcha = captcha();
+        let mut solution = solve(&captcha.challenge().unwrap());
+        solution["number"] = (solution["number"].as_u64().unwrap() + 1).into();
+
+        assert!(!verify(&captcha, &solution).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_signatures() {
+        let captcha = captcha();
+        let mut solution = solve(&captcha.challenge().unwrap());
+        solution["signature"] = "0".repeat(64).into();
+        assert!(!verify(&captcha, &solution).await);
+
+        // Challenges signed with another key are not accepted either
+        let other = PowCaptcha::new(1000, "other key".to_owned());
+        let solution = solve(&other.challenge().unwrap());
+        assert!(!verify(&captcha, &solution).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_salts() {
+        let captcha = captcha();
+        let challenge = captcha.challenge().unwrap();
+        let solution = solve(&challenge);
+
+        // Extending the expiry changes the salt, so the signed challenge no longer matches
+        let (token, expires) = challenge.salt.rsplit_once("?expires=").unwrap();
+        let expires = expires.parse::<i64>().unwrap() + 3600;
+        let mut tampered = solution.clone();
+        tampered["salt"] = format!("{token}?expires={expires}").into();
+        assert!(!verify(&captcha, &tampered).await);
+
+        // Salts without an expiry are rejected, even with a matching challenge
+        let salt = "no expiry";
+        let challenge = pow_digest(salt, 1);
+        let unsigned = serde_json::json!({
+            "challenge": challenge,
+            "number": 1,
+            "salt": salt,
+            "signature": generate_hash(&format!("pow:{challenge}"), "test key"),
+        });
+        assert!(!verify(&captcha, &unsigned).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_challenges() {
+        let captcha = captcha();
+        let salt = format!("token?expires={}", Utc::now().timestamp() - 1);
+        let challenge = pow_digest(&salt, 1);
+        let expired = serde_json::json!({
+            "challenge": challenge,
+            "number": 1,
+            "salt": salt,
+            "signature": generate_hash(&format!("pow:{challenge}"), "test key"),
+        });
+
+        assert!(!verify(&captcha, &expired).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_garbage() {
+        let captcha = captcha();
+        for response in ["", "null", "{}", "not json"] {
+            assert!(!captcha.verify(response, "203.0.113.7").await.unwrap());
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{Context, bail};
+use chrono::{TimeDelta, Utc};
+use sqlx::PgPool;
+
+use crate::admin::{
+    delete_user, list_users, set_user_blocked, set_user_owner, set_user_priorities, set_user_role,
+};
+use crate::blocklist::{RuleAction, RuleKind, add_rule, delete_rule, list_rules};
+use crate::roles::PRIORITIES;
+use crate::routes::invite::create_invite;
+
+// Usage: enviame invite [--role <role>] [--uses <count>] [--days <days>]
+pub async fn run_invite(db: &PgPool, args: &[String]) -> anyhow::Result<()> {
+    let mut role = 0;
+    let mut max_uses = None;
+    let mut expires_time = None;
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .with_context(|| format!("Missing value for {flag}"))?;
+
+        match flag.as_str() {
+            "--role" => role = value.parse().context("Role must be a number")?,
+            "--uses" => max_uses = Some(value.parse().context("Uses must be a number")?),
+            "--days" => {
+                let days = value.parse().context("Days must be a number")?;
+                expires_time = Some(
+                    TimeDelta::try_days(days)
+                        .and_then(|days| Utc::now().checked_add_signed(days))
+                        .context("Days is out of range")?,
+                );
+            }
+            _ => bail!("Unknown option {flag}"),
+        }
+    }
+
+    let link = create_invite(db, role, max_uses, expires_time).await?;
+    println!("{link}");
+
+    Ok(())
+}
+
+// Usage: enviame admin list [search]
+//        enviame admin role <uid> <role>
+//        enviame admin owner <uid>
+//        enviame admin priorities <uid> <priority,...|default>
+//        enviame admin block|unblock|delete <uid>
+//        enviame admin rules
+//        enviame admin rule block|allow <uid|email|domain|ip> <value> [note]
+//        enviame admin rule delete <id>
+pub async fn run_admin(db: &PgPool, args: &[String]) -> anyhow::Result<()> {
+    let parse_uid = |uid: Option<&String>| -> anyhow::Result<i32> {
+        uid.context("Missing user id")?
+            .parse()
+            .context("User id must be a number")
+    };
+
+    let found = match args.first().map(String::as_str) {
+        Some("list") => {
+            let search = args.get(1).map(String::as_str).unwrap_or_default();
+            for user in list_users(db, search).await? {
+                println!(
+                    "{}\t{}\t{}\t{}\trole {}\t{}{}{}",
+                    user.uid,
+                    user.added_time,
+                    user.name,
+                    user.email,
+                    user.role,
+                    user.status,
+                    if user.verified { "\tverified" } else { "" },
+                    user.allowed_priorities
+                        .map(|p| format!("\tpriorities {}", p.join(",")))
+                        .unwrap_or_default()
+                );
+            }
+            return Ok(());
+        }
+        Some("role") => {
+            let uid = parse_uid(args.get(1))?;
+            let role = args
+                .get(2)
+                .context("Missing role")?
+                .parse()
+                .context("Role must be a number")?;
+            set_user_role(db, uid, role).await?
+        }
+        Some("owner") => set_user_owner(db, parse_uid(args.get(1))?).await?,
+        Some("priorities") => {
+            let uid = parse_uid(args.get(1))?;
+            let priorities = match args.get(2).context("Missing priorities")?.as_str() {
+                "default" => None,
+                priorities => {
+                    let priorities = priorities
+                        .split(',')
+                        .map(|p| p.trim().to_lowercase())
+                        .collect::<Vec<_>>();
+                    if let Some(p) = priorities
+                        .iter()
+                        .find(|p| !PRIORITIES.contains(&p.as_str()))
+                    {
+                        bail!("Unknown priority {p}");
+                    }
+                    Some(priorities)
+                }
+            };
+            set_user_priorities(db, uid, priorities.as_deref()).await?
+        }
+        Some("block") => set_user_blocked(db, parse_uid(args.get(1))?, true).await?,
+        Some("unblock") => set_user_blocked(db, parse_uid(args.get(1))?, false).await?,
+        Some("delete") => delete_user(db, parse_uid(args.get(1))?).await?,
+        Some("rules") => {
+            for rule in list_rules(db).await? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    rule.id, rule.created_time, rule.action, rule.kind, rule.value, rule.note
+                );
+            }
+            return Ok(());
+        }
+        Some("rule") => match args.get(1).map(String::as_str) {
+            Some("delete") => {
+                let id = args
+                    .get(2)
+                    .context("Missing rule id")?
+                    .parse()
+                    .context("Rule id must be a number")?;
+                if !delete_rule(db, id).await? {
+                    bail!("Rule not found");
+                }
+                true
+            }
+            action => {
+                let action = action
+                    .and_then(RuleAction::parse)
+                    .context("Rule action must be block, allow or delete")?;
+                let kind = args
+                    .get(2)
+                    .and_then(|k| RuleKind::parse(k))
+                    .context("Rule kind must be uid, email, domain or ip")?;
+                let value = args
+                    .get(3)
+                    .and_then(|v| kind.normalise(v))
+                    .with_context(|| format!("Missing or invalid {} rule value", kind.as_str()))?;
+                let note = args.get(4..).unwrap_or_default().join(" ");
+                let id = add_rule(db, kind, &value, action, &note).await?;
+                println!("Rule {id} saved");
+                return Ok(());
+            }
+        },
+        Some(command) => bail!("Unknown admin command {command}"),
+        None => bail!("Missing admin command"),
+    };
+
+    if !found {
+        bail!("User or role not found");
+    }
+    println!("Done");
+
+    Ok(())
+}
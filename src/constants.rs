@@ -13,12 +13,18 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use chrono::TimeDelta;
 use chrono_tz::Tz;
 use lettre::{
     SmtpTransport,
     transport::smtp::authentication::{Credentials, Mechanism},
 };
-use std::{env, sync::LazyLock};
+use std::{collections::HashMap, env, sync::LazyLock};
+
+use crate::ratelimit::RateLimit;
+use crate::roles::PRIORITIES;
+use crate::state::SleepHours;
+use crate::utils::IpRange;
 
 // --- Calendar ---
 // Your timezone, used as the timezone of "00:00" for all-day events and the timezone for sleep-time blocking periods
@@ -30,6 +36,104 @@ pub static DEFAULT_TZ: LazyLock<Tz> = LazyLock::new(|| {
         .unwrap()
 });
 
+// Nightly sleep period in LOCAL_TIMEZONE, as "HH:MM-HH:MM", blocked like a busy calendar event by the calendar worker. Defaults to 23:00-08:00
+pub static SLEEP_HOURS: LazyLock<SleepHours> = LazyLock::new(|| {
+    let sleep_hours = env::var("SLEEP_HOURS").unwrap_or_else(|_| "23:00-08:00".to_owned());
+    SleepHours::parse(&sleep_hours, *DEFAULT_TZ).expect("SLEEP_HOURS must look like 23:00-08:00")
+});
+
+// --- Priority Escalation ---
+// Undelivered standard messages are escalated to urgent this long before their deadline
+pub const ESCALATE_URGENT_BEFORE: TimeDelta = TimeDelta::minutes(60);
+
+// Undelivered messages are escalated to immediate this long before their deadline
+pub const ESCALATE_IMMEDIATE_BEFORE: TimeDelta = TimeDelta::minutes(10);
+
+// --- Guests ---
+// Priorities guests can send, as a comma separated list. Defaults to standard only
+pub static GUEST_PRIORITIES: LazyLock<Vec<&'static str>> = LazyLock::new(|| {
+    let configured = env::var("GUEST_PRIORITIES").unwrap_or_else(|_| "standard".to_owned());
+    PRIORITIES
+        .into_iter()
+        .filter(|p| {
+            configured
+                .split(',')
+                .any(|c| c.trim().eq_ignore_ascii_case(p))
+        })
+        .collect()
+});
+
+// How long the code emailed to a guest stays valid
+pub const GUEST_CODE_LIFETIME: TimeDelta = TimeDelta::minutes(10);
+
+// Wrong codes a guest can enter before their message has to be submitted again
+pub const GUEST_CODE_ATTEMPTS: i32 = 5;
+
+// --- Sessions ---
+// How long a session stays valid after logging in with a login link
+pub const SESSION_LIFETIME: TimeDelta = TimeDelta::days(30);
+
+// Whether login links are single-use and expire, instead of being permanent
+pub static ONE_TIME_LOGIN_LINKS: LazyLock<bool> = LazyLock::new(|| {
+    env::var("ONE_TIME_LOGIN_LINKS").is_ok_and(|v| v.trim().eq_ignore_ascii_case("true"))
+});
+
+// How long a one-time login link stays valid
+pub const ONE_TIME_LINK_LIFETIME: TimeDelta = TimeDelta::minutes(15);
+
+// How long the link confirming a new email address stays valid
+pub const EMAIL_CHANGE_LINK_LIFETIME: TimeDelta = TimeDelta::hours(24);
+
+// --- Rate Limiting ---
+// Requests allowed per client IP and per user on each rate limited route, as <requests>/<seconds> or off
+pub static RATE_LIMITS: LazyLock<HashMap<&'static str, RateLimit>> = LazyLock::new(|| {
+    [
+        ("/api/submit", "RATE_LIMIT_SUBMIT", "10/3600"),
+        ("/api/apply", "RATE_LIMIT_APPLY", "5/3600"),
+        ("/api/resendlink", "RATE_LIMIT_RESENDLINK", "3/3600"),
+        ("/api/login", "RATE_LIMIT_LOGIN", "30/60"),
+        (
+            "/api/passkey/login/start",
+            "RATE_LIMIT_PASSKEY_LOGIN",
+            "10/60",
+        ),
+        (
+            "/api/passkey/login/finish",
+            "RATE_LIMIT_PASSKEY_LOGIN",
+            "10/60",
+        ),
+        ("/api/revoke", "RATE_LIMIT_REVOKE", "3/3600"),
+        ("/api/message/confirm", "RATE_LIMIT_CONFIRM", "10/600"),
+        ("/api/account/email", "RATE_LIMIT_EMAIL_CHANGE", "3/3600"),
+    ]
+    .into_iter()
+    .filter_map(|(route, var, default)| {
+        let limit = env::var(var).unwrap_or_else(|_| default.to_owned());
+        if limit.trim().eq_ignore_ascii_case("off") {
+            return None;
+        }
+        let limit = RateLimit::parse(&limit)
+            .unwrap_or_else(|| panic!("{var} must be <requests>/<seconds> or off"));
+        Some((route, limit))
+    })
+    .collect()
+});
+
+// --- Proxies ---
+// Addresses of reverse proxies whose forwarding headers are trusted, as a comma separated list of CIDR ranges.
+//    Defaults to loopback only, add the docker bridge network when running behind compose
+pub static TRUSTED_PROXIES: LazyLock<Vec<IpRange>> = LazyLock::new(|| {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_else(|_| "127.0.0.0/8,::1".to_owned())
+        .split(',')
+        .filter(|range| !range.trim().is_empty())
+        .map(|range| {
+            IpRange::parse(range)
+                .unwrap_or_else(|| panic!("TRUSTED_PROXIES has an invalid range {range}"))
+        })
+        .collect()
+});
+
 // --- Formats ---
 // Datetime format, used when sending emails
 pub const EMAIL_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
@@ -58,13 +162,46 @@ pub const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub static MID_HASH_KEY: LazyLock<String> =
     LazyLock::new(|| env::var("HASH_KEY").expect("HASH_KEY must be set"));
 
-// Recaptcha site key, embedded in HTML
-pub static RECAPTCHA_SITE_KEY: LazyLock<String> =
-    LazyLock::new(|| env::var("RECAPTCHA_SITE_KEY").expect("RECAPTCHA_SITE_KEY must be set"));
+// Session Key, used to sign session cookies. Defaults to HASH_KEY if unset
+pub static SESSION_KEY: LazyLock<String> =
+    LazyLock::new(|| env::var("SESSION_KEY").unwrap_or((*MID_HASH_KEY).clone()));
+
+// Admin token, lets scripts use the admin API without a session. Admin API only accepts sessions of admin users if unset
+pub static ADMIN_TOKEN: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()));
+
+// CAPTCHA provider of the application and login link forms: recaptcha, hcaptcha, turnstile, pow or none. Defaults to recaptcha
+pub static CAPTCHA_PROVIDER: LazyLock<String> = LazyLock::new(|| {
+    env::var("CAPTCHA_PROVIDER")
+        .unwrap_or_else(|_| "recaptcha".to_owned())
+        .trim()
+        .to_lowercase()
+});
+
+// Largest number the proof-of-work CAPTCHA can hide, browsers try half as many hashes on average. Defaults to 100000
+pub static CAPTCHA_POW_DIFFICULTY: LazyLock<u64> = LazyLock::new(|| {
+    env::var("CAPTCHA_POW_DIFFICULTY")
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .unwrap_or(100_000)
+});
+
+// How long a proof-of-work challenge can be solved for
+pub const POW_CHALLENGE_LIFETIME: TimeDelta = TimeDelta::minutes(10);
 
-// Recaptcha secret key, used when verifying requests
-pub static RECAPTCHA_SECRET_KEY: LazyLock<String> =
-    LazyLock::new(|| env::var("RECAPTCHA_SECRET_KEY").expect("RECAPTCHA_SECRET_KEY must be set"));
+// CAPTCHA site key, embedded in HTML. Falls back to RECAPTCHA_SITE_KEY
+pub static CAPTCHA_SITE_KEY: LazyLock<String> = LazyLock::new(|| {
+    env::var("CAPTCHA_SITE_KEY")
+        .or_else(|_| env::var("RECAPTCHA_SITE_KEY"))
+        .expect("CAPTCHA_SITE_KEY must be set")
+});
+
+// CAPTCHA secret key, used when verifying requests. Falls back to RECAPTCHA_SECRET_KEY
+pub static CAPTCHA_SECRET_KEY: LazyLock<String> = LazyLock::new(|| {
+    env::var("CAPTCHA_SECRET_KEY")
+        .or_else(|_| env::var("RECAPTCHA_SECRET_KEY"))
+        .expect("CAPTCHA_SECRET_KEY must be set")
+});
 
 // --- SMTP Email Configurations ---
 // Recipient address of all notification emails, and reply_to address of all user emails
@@ -14,36 +14,75 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use axum::{
-    Router,
+    Router, middleware,
     routing::{get, post},
 };
 use axum_csrf::{CsrfConfig, CsrfLayer};
 use sqlx::PgPool;
 use std::{env, net::SocketAddr, sync::Arc};
-use tokio::{net::TcpListener, sync::RwLock};
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, RwLock},
+};
+use webauthn_rs::prelude::{Url, WebauthnBuilder};
 
 mod routes;
 use routes::{
+    account::{handle_account_email, handle_account_name, handle_email_change_confirm},
+    admin::{
+        handle_admin_block, handle_admin_delete, handle_admin_priorities, handle_admin_role,
+        handle_admin_rule_add, handle_admin_rule_delete, handle_admin_rules, handle_admin_users,
+    },
     apply::handle_apply,
+    approval::handle_application_decision,
     assets::serve_embedded_assets,
+    block::handle_block_sender,
     calendar::handle_calendar_status_query,
+    captcha::handle_captcha_challenge,
     form::handle_form_submission,
-    login::handle_login,
-    message::handle_message_query,
-    pages::{serve_about_page, serve_apply_form, serve_index, serve_resend_link_form},
+    guest::handle_message_confirm,
+    login::{handle_login, handle_logout},
+    message::{handle_message_cancel, handle_message_edit, handle_message_query},
+    pages::{
+        serve_about_page, serve_application_page, serve_apply_form, serve_block_sender_page,
+        serve_email_change_page, serve_index, serve_resend_link_form, serve_sessions_page,
+    },
+    passkey::{
+        handle_passkey_login_finish, handle_passkey_login_start, handle_passkey_register_finish,
+        handle_passkey_register_start, relying_party_id,
+    },
     resend_link::handle_resend_link,
+    revoke::handle_revoke_all,
+    sessions::{handle_session_revoke, handle_sessions_list},
     version::handle_version,
 };
 
 mod workers;
 use workers::{calendar::calendar_worker, email::email_worker};
 
+mod admin;
+
+mod blocklist;
+
+mod captcha;
+use captcha::captcha_from_config;
+
+mod cli;
+
 mod constants;
+use constants::HOMEPAGE_URL;
 
 mod utils;
 
+mod ratelimit;
+use ratelimit::{RateLimiter, rate_limit};
+
+mod roles;
+
+mod session;
+
 mod state;
-use state::{AppState, CalendarCache};
+use state::{AppState, CalendarCache, PasskeyCeremonies};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -52,15 +91,38 @@ async fn main() -> anyhow::Result<()> {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let db_pool = PgPool::connect(&database_url).await?;
 
+    // Subcommands run against the database and exit without starting the server
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Some(command) = args.first() {
+        return match command.as_str() {
+            "invite" => cli::run_invite(&db_pool, &args[1..]).await,
+            "admin" => cli::run_admin(&db_pool, &args[1..]).await,
+            _ => anyhow::bail!("Unknown command {command}"),
+        };
+    }
+
     let initial_cache = CalendarCache {
         is_busy: true,
         timestamp: "2099-12-31 23:59".to_owned(),
+        busy_until: None,
     };
     let initial_cache = Arc::new(RwLock::new(initial_cache));
 
+    let rp_origin = Url::parse(&HOMEPAGE_URL).expect("HOMEPAGE_URL must be a valid URL");
+    let rp_id = relying_party_id(&rp_origin);
+    let webauthn = WebauthnBuilder::new(&rp_id, &rp_origin)
+        .expect("HOMEPAGE_URL must be a valid WebAuthn origin")
+        .rp_name("Enviame")
+        .build()
+        .expect("WebAuthn configuration must be valid");
+
     let state = AppState {
         db: db_pool,
         status: initial_cache,
+        webauthn: Arc::new(webauthn),
+        ceremonies: Arc::new(Mutex::new(PasskeyCeremonies::default())),
+        rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
+        captcha: captcha_from_config(),
     };
 
     let port: u16 = env::var("APP_PORT")
@@ -84,14 +146,56 @@ async fn main() -> anyhow::Result<()> {
         .route("/apply", get(serve_apply_form))
         .route("/about", get(serve_about_page))
         .route("/resendlink", get(serve_resend_link_form))
+        .route("/sessions", get(serve_sessions_page))
+        .route("/application", get(serve_application_page))
+        .route("/block", get(serve_block_sender_page))
+        .route("/account/email", get(serve_email_change_page))
         .route("/api/login", get(handle_login))
+        .route("/api/logout", post(handle_logout))
         .route("/api/submit", post(handle_form_submission))
         .route("/api/apply", post(handle_apply))
+        .route("/api/application", post(handle_application_decision))
+        .route("/api/block", post(handle_block_sender))
         .route("/api/resendlink", post(handle_resend_link))
+        .route("/api/revoke", post(handle_revoke_all))
+        .route("/api/account/name", post(handle_account_name))
+        .route("/api/account/email", post(handle_account_email))
+        .route(
+            "/api/account/email/confirm",
+            post(handle_email_change_confirm),
+        )
+        .route("/api/sessions", get(handle_sessions_list))
+        .route("/api/sessions/revoke", post(handle_session_revoke))
+        .route(
+            "/api/passkey/register/start",
+            post(handle_passkey_register_start),
+        )
+        .route(
+            "/api/passkey/register/finish",
+            post(handle_passkey_register_finish),
+        )
+        .route("/api/passkey/login/start", post(handle_passkey_login_start))
+        .route(
+            "/api/passkey/login/finish",
+            post(handle_passkey_login_finish),
+        )
         .route("/api/version", get(handle_version))
         .route("/api/message", get(handle_message_query))
+        .route("/api/message/cancel", post(handle_message_cancel))
+        .route("/api/message/edit", post(handle_message_edit))
+        .route("/api/message/confirm", post(handle_message_confirm))
         .route("/api/calendar", get(handle_calendar_status_query))
+        .route("/api/captcha/challenge", get(handle_captcha_challenge))
+        .route("/api/admin/users", get(handle_admin_users))
+        .route("/api/admin/users/role", post(handle_admin_role))
+        .route("/api/admin/users/priorities", post(handle_admin_priorities))
+        .route("/api/admin/users/block", post(handle_admin_block))
+        .route("/api/admin/users/delete", post(handle_admin_delete))
+        .route("/api/admin/rules", get(handle_admin_rules))
+        .route("/api/admin/rules/add", post(handle_admin_rule_add))
+        .route("/api/admin/rules/delete", post(handle_admin_rule_delete))
         .route("/assets/{*file}", get(serve_embedded_assets))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit))
         .layer(CsrfLayer::new(csrf_config))
         .with_state(state);
 
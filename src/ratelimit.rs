@@ -0,0 +1,245 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::constants::RATE_LIMITS;
+use crate::session::get_session_user;
+use crate::state::AppState;
+use crate::utils::get_client_ip;
+
+// Buckets are only pruned once there are this many, dropping those that have refilled
+const PRUNE_THRESHOLD: usize = 1000;
+
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    // Bucket size, the number of requests that can be made at once
+    requests: u32,
+    // Time for an empty bucket to refill
+    per: Duration,
+}
+
+impl RateLimit {
+    // Parses <requests>/<seconds>
+    pub fn parse(limit: &str) -> Option<Self> {
+        let (requests, seconds) = limit.trim().split_once('/')?;
+        let requests = requests.trim().parse().ok().filter(|r| *r > 0)?;
+        let seconds = seconds.trim().parse().ok().filter(|s| *s > 0)?;
+
+        Some(RateLimit {
+            requests,
+            per: Duration::from_secs(seconds),
+        })
+    }
+
+    // Tokens added to a bucket per second
+    fn refill_rate(&self) -> f64 {
+        self.requests as f64 / self.per.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    full_at: Instant,
+}
+
+// Token buckets of rate limited routes, kept in memory and keyed by route and client
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: HashMap<(&'static str, String), Bucket>,
+}
+
+impl RateLimiter {
+    // Takes a token from the bucket of every key if all of them have one, otherwise returns how long until they do
+    pub fn check(
+        &mut self,
+        route: &'static str,
+        limit: RateLimit,
+        keys: &[String],
+    ) -> Result<(), Duration> {
+        self.check_at(Instant::now(), route, limit, keys)
+    }
+
+    fn check_at(
+        &mut self,
+        now: Instant,
+        route: &'static str,
+        limit: RateLimit,
+        keys: &[String],
+    ) -> Result<(), Duration> {
+        let capacity = limit.requests as f64;
+        let rate = limit.refill_rate();
+
+        if self.buckets.len() >= PRUNE_THRESHOLD {
+            self.buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+
+        let mut wait = Duration::ZERO;
+        for key in keys {
+            let bucket = self.buckets.entry((route, key.clone())).or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+                full_at: now,
+            });
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+            bucket.updated = now;
+
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - bucket.tokens) / rate));
+            }
+        }
+
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for key in keys {
+            if let Some(bucket) = self.buckets.get_mut(&(route, key.clone())) {
+                bucket.tokens -= 1.0;
+                bucket.full_at = now + Duration::from_secs_f64((capacity - bucket.tokens) / rate);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Requests to rate limited routes take a token from the bucket of the client IP,
+//    and from the bucket of the user if they are signed in
+pub async fn rate_limit(
+    State(state): State<AppState>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some((route, limit)) = RATE_LIMITS.get_key_value(request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    let mut keys = vec![format!(
+        "ip:{}",
+        get_client_ip(request.headers(), remote_addr)
+    )];
+    if let Some(user) = get_session_user(&state, request.headers()).await {
+        keys.push(format!("uid:{}", user.uid));
+    }
+
+    let result = state.rate_limiter.lock().await.check(route, *limit, &keys);
+
+    match result {
+        Ok(()) => next.run(request).await,
+        Err(wait) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, wait.as_secs_f64().ceil().to_string())],
+            "Too many requests. Please try again later.",
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limits() {
+        let limit = RateLimit::parse(" 10 / 3600 ").unwrap();
+        assert_eq!(limit.requests, 10);
+        assert_eq!(limit.per, Duration::from_secs(3600));
+
+        for invalid in [
+            "", "off", "10", "0/60", "10/0", "-1/60", "ten/60", "10/60/2",
+        ] {
+            assert!(RateLimit::parse(invalid).is_none(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let limit = RateLimit::parse("2/10").unwrap();
+        let keys = ["ip:203.0.113.7".to_owned()];
+        let start = Instant::now();
+        let mut limiter = RateLimiter::default();
+
+        // A full bucket allows a burst, then one more request every 5 seconds
+        assert!(limiter.check_at(start, "/api/submit", limit, &keys).is_ok());
+        assert!(limiter.check_at(start, "/api/submit", limit, &keys).is_ok());
+        let wait = limiter
+            .check_at(start, "/api/submit", limit, &keys)
+            .unwrap_err();
+        assert_eq!(wait.as_secs_f64().round(), 5.0);
+
+        let later = start + Duration::from_secs(4);
+        assert!(
+            limiter
+                .check_at(later, "/api/submit", limit, &keys)
+                .is_err()
+        );
+        let later = start + Duration::from_secs(5);
+        assert!(limiter.check_at(later, "/api/submit", limit, &keys).is_ok());
+        assert!(
+            limiter
+                .check_at(later, "/api/submit", limit, &keys)
+                .is_err()
+        );
+
+        // Refills stop at the bucket size
+        let much_later = start + Duration::from_secs(3600);
+        assert!(
+            limiter
+                .check_at(much_later, "/api/submit", limit, &keys)
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .check_at(much_later, "/api/submit", limit, &keys)
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .check_at(much_later, "/api/submit", limit, &keys)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn buckets_are_separate() {
+        let limit = RateLimit::parse("1/60").unwrap();
+        let ip = ["ip:203.0.113.7".to_owned()];
+        let user = ["uid:1".to_owned()];
+        let both = ["ip:203.0.113.7".to_owned(), "uid:1".to_owned()];
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+
+        assert!(limiter.check_at(now, "/api/submit", limit, &ip).is_ok());
+        assert!(limiter.check_at(now, "/api/apply", limit, &ip).is_ok());
+
+        // A request is refused if any of its buckets is empty, without using up the others
+        assert!(limiter.check_at(now, "/api/submit", limit, &both).is_err());
+        assert!(limiter.check_at(now, "/api/submit", limit, &user).is_ok());
+    }
+}
@@ -0,0 +1,165 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Capabilities of each user role, as configured in the roles table
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use sqlx::{PgExecutor, PgPool};
+
+use crate::constants::DEFAULT_TZ;
+
+// Message priorities, from lowest to highest
+pub const PRIORITIES: [&str; 3] = ["standard", "urgent", "immediate"];
+
+pub struct Role {
+    // Shown as the sender type of messages from verified users with this role
+    pub name: String,
+    pub allowed_priorities: Vec<String>,
+    // Maximum number of messages per 24 hours, unlimited if unset
+    pub daily_quota: Option<i32>,
+    // Colour of the tick displayed next to the user's name
+    pub badge_colour: String,
+    // Standard messages are delivered even while the calendar is busy or during sleep hours
+    pub bypass_sleep: bool,
+    // Can use the admin API with their session
+    pub admin: bool,
+    // Urgent and immediate messages the role can send per week, unlimited if unset
+    pub weekly_urgent_budget: Option<i32>,
+    pub weekly_immediate_budget: Option<i32>,
+    // Messages over budget are sent with a lower priority still in budget, instead of being rejected
+    pub downgrade_over_budget: bool,
+}
+
+impl Role {
+    // Priorities a user with this role may send, from lowest to highest.
+    //    A user's own allowed priorities take precedence over their role's
+    pub fn allowed_priorities_for(&self, user_priorities: Option<&[String]>) -> Vec<&'static str> {
+        let allowed = user_priorities.unwrap_or(&self.allowed_priorities);
+        PRIORITIES
+            .into_iter()
+            .filter(|p| allowed.iter().any(|a| a == p))
+            .collect()
+    }
+
+    pub fn weekly_budget(&self, priority: &str) -> Option<i32> {
+        match priority {
+            "urgent" => self.weekly_urgent_budget,
+            "immediate" => self.weekly_immediate_budget,
+            _ => None,
+        }
+    }
+}
+
+// Budgets refill every Monday at 00:00 local time.
+//    Returns the start of the current budget period and the time of the next refill
+pub fn budget_period() -> (DateTime<Utc>, DateTime<Utc>) {
+    budget_period_at(Utc::now(), *DEFAULT_TZ)
+}
+
+fn budget_period_at(now: DateTime<Utc>, tz: Tz) -> (DateTime<Utc>, DateTime<Utc>) {
+    let today = now.with_timezone(&tz).date_naive();
+    let monday = today - TimeDelta::days(today.weekday().num_days_from_monday() as i64);
+
+    let start_of = |date: NaiveDate| {
+        let midnight = date.and_time(NaiveTime::MIN);
+        tz.from_local_datetime(&midnight)
+            .earliest()
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_else(|| midnight.and_utc())
+    };
+
+    (start_of(monday), start_of(monday + TimeDelta::days(7)))
+}
+
+// Messages of this priority the user can still send in the current budget period, unlimited if None.
+//    Messages escalated to the priority count too, and a message being edited does not count against its own budget
+pub async fn remaining_budget(
+    db: impl PgExecutor<'_>,
+    role: &Role,
+    uid: i32,
+    priority: &str,
+    editing: Option<i32>,
+) -> Option<i64> {
+    let budget = role.weekly_budget(priority)?;
+    let (period_start, _) = budget_period();
+
+    let used = sqlx::query!(
+        "SELECT COUNT(*) AS \"count!\" FROM messages WHERE user_uid = $1 AND (submitted_priority = $2 OR priority = $2) AND status <> 'cancelled' AND submitted_time >= $3 AND id IS DISTINCT FROM $4",
+        uid,
+        priority,
+        period_start,
+        editing
+    )
+    .fetch_one(db)
+    .await
+    .unwrap()
+    .count;
+
+    Some((budget as i64 - used).max(0))
+}
+
+pub async fn get_role(db: &PgPool, id: i32) -> Role {
+    sqlx::query_as!(
+        Role,
+        "SELECT name, allowed_priorities, daily_quota, badge_colour, bypass_sleep, admin, weekly_urgent_budget, weekly_immediate_budget, downgrade_over_budget FROM roles WHERE id = $1",
+        id
+    )
+    .fetch_one(db)
+    .await
+    .expect("Users must have an existing role")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn budget_period_in_utc() {
+        let week = (at("2026-01-05T00:00:00Z"), at("2026-01-12T00:00:00Z"));
+
+        assert_eq!(budget_period_at(at("2026-01-07T12:00:00Z"), Tz::UTC), week);
+        assert_eq!(budget_period_at(at("2026-01-05T00:00:00Z"), Tz::UTC), week);
+        assert_eq!(budget_period_at(at("2026-01-11T23:59:59Z"), Tz::UTC), week);
+        assert_eq!(
+            budget_period_at(at("2026-01-12T00:00:00Z"), Tz::UTC),
+            (at("2026-01-12T00:00:00Z"), at("2026-01-19T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn budget_period_in_local_time() {
+        // Already Monday 01:00 in Tokyo while it is still Sunday in UTC
+        assert_eq!(
+            budget_period_at(at("2026-01-11T16:00:00Z"), Tz::Asia__Tokyo),
+            (at("2026-01-11T15:00:00Z"), at("2026-01-18T15:00:00Z"))
+        );
+
+        // Still Sunday 22:00 in New York while it is already Monday in UTC
+        assert_eq!(
+            budget_period_at(at("2026-01-12T03:00:00Z"), Tz::America__New_York),
+            (at("2026-01-05T05:00:00Z"), at("2026-01-12T05:00:00Z"))
+        );
+
+        // The week clocks go forward in London is an hour shorter
+        assert_eq!(
+            budget_period_at(at("2026-03-25T12:00:00Z"), Tz::Europe__London),
+            (at("2026-03-23T00:00:00Z"), at("2026-03-29T23:00:00Z"))
+        );
+    }
+}
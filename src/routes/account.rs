@@ -0,0 +1,348 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use askama::Template;
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use axum_csrf::CsrfToken;
+use reqwest::Url;
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+use crate::blocklist::is_sender_blocked;
+use crate::constants::{
+    CARGO_PKG_VERSION, EMAIL_CHANGE_LINK_LIFETIME, EMAIL_DATETIME_FORMAT, FROM_STANDARD,
+    HOMEPAGE_URL, MID_HASH_KEY, NOTIFICATION_EMAIL,
+};
+use crate::session::get_session_user;
+use crate::state::AppState;
+use crate::utils::{
+    check_hash, generate_hash, get_client_ip, get_ip_prefix, normalise_email, send_email,
+};
+
+#[derive(Template)]
+#[template(path = "email_change_link.html")]
+struct EmailChangeLinkTemplate<'a> {
+    name: &'a str,
+    link: &'a str,
+    valid_hours: i64,
+    version: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email_change_requested.html")]
+struct EmailChangeRequestedTemplate<'a> {
+    name: &'a str,
+    new_email: &'a str,
+    valid_hours: i64,
+    requested_time: &'a str,
+    ip_prefix: &'a str,
+    link: &'a str,
+    version: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email_changed.html")]
+struct EmailChangedTemplate<'a> {
+    name: &'a str,
+    new_email: &'a str,
+    changed_time: &'a str,
+    version: &'a str,
+}
+
+#[derive(Deserialize)]
+pub struct AccountNameRequest {
+    csrf_token: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct AccountEmailRequest {
+    csrf_token: String,
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailChangeConfirmRequest {
+    csrf_token: String,
+    uid: i32,
+    email: String,
+    expires: i64,
+    hash: String,
+}
+
+// The link is signed for the user's current address as well, so it stops working once the email changes
+fn email_change_payload(uid: i32, current_email: &str, new_email: &str, expires: i64) -> String {
+    format!("email:{uid}:{current_email}:{new_email}:{expires}")
+}
+
+async fn send_email_change_link(
+    uid: i32,
+    name: &str,
+    current_email: &str,
+    new_email: &str,
+) -> anyhow::Result<()> {
+    let expires = (chrono::Utc::now() + EMAIL_CHANGE_LINK_LIFETIME).timestamp();
+    let hash = generate_hash(
+        &email_change_payload(uid, current_email, new_email, expires),
+        &MID_HASH_KEY,
+    );
+    let link = Url::parse_with_params(
+        &format!("{}account/email", *HOMEPAGE_URL),
+        &[
+            ("uid", uid.to_string()),
+            ("email", new_email.to_owned()),
+            ("expires", expires.to_string()),
+            ("hash", hash),
+        ],
+    )?;
+
+    let subject = "[Enviame] Confirm your new email address";
+    let link_template = EmailChangeLinkTemplate {
+        name,
+        link: link.as_str(),
+        valid_hours: EMAIL_CHANGE_LINK_LIFETIME.num_hours(),
+        version: CARGO_PKG_VERSION,
+    };
+    let link_body = link_template
+        .render()
+        .expect("Email change link email failed to render");
+
+    send_email(
+        &FROM_STANDARD,
+        new_email,
+        &NOTIFICATION_EMAIL,
+        subject,
+        &link_body,
+    )
+    .await
+}
+
+// Lets the current address react before the change is confirmed
+async fn send_email_change_requested_notice(
+    name: &str,
+    current_email: &str,
+    new_email: &str,
+    ip_prefix: &str,
+) -> anyhow::Result<()> {
+    let subject = "[Enviame] Email address change requested";
+    let requested_time = chrono::Utc::now().format(EMAIL_DATETIME_FORMAT).to_string();
+    let link = format!("{}sessions", *HOMEPAGE_URL);
+    let requested_template = EmailChangeRequestedTemplate {
+        name,
+        new_email,
+        valid_hours: EMAIL_CHANGE_LINK_LIFETIME.num_hours(),
+        requested_time: &requested_time,
+        ip_prefix,
+        link: &link,
+        version: CARGO_PKG_VERSION,
+    };
+    let requested_body = requested_template
+        .render()
+        .expect("Email change requested email failed to render");
+
+    send_email(
+        &FROM_STANDARD,
+        current_email,
+        &NOTIFICATION_EMAIL,
+        subject,
+        &requested_body,
+    )
+    .await
+}
+
+async fn send_email_changed_notice(
+    name: &str,
+    old_email: &str,
+    new_email: &str,
+) -> anyhow::Result<()> {
+    let subject = "[Enviame] Your email address was changed";
+    let changed_time = chrono::Utc::now().format(EMAIL_DATETIME_FORMAT).to_string();
+    let changed_template = EmailChangedTemplate {
+        name,
+        new_email,
+        changed_time: &changed_time,
+        version: CARGO_PKG_VERSION,
+    };
+    let changed_body = changed_template
+        .render()
+        .expect("Email changed email failed to render");
+
+    send_email(
+        &FROM_STANDARD,
+        old_email,
+        &NOTIFICATION_EMAIL,
+        subject,
+        &changed_body,
+    )
+    .await
+}
+
+pub async fn handle_account_name(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<AccountNameRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let Some(user) = get_session_user(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Not logged in.").into_response();
+    };
+
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Name is required.").into_response();
+    }
+
+    sqlx::query!("UPDATE users SET name = $1 WHERE uid = $2", name, user.uid)
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+    (StatusCode::OK, "Your name has been updated.").into_response()
+}
+
+// The new address only replaces the current one once it confirms the emailed link
+pub async fn handle_account_email(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<AccountEmailRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let Some(user) = get_session_user(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Not logged in.").into_response();
+    };
+
+    let Some(email) = normalise_email(&payload.email) else {
+        return (StatusCode::BAD_REQUEST, "Email invalid.").into_response();
+    };
+
+    if email == user.email {
+        return (StatusCode::BAD_REQUEST, "This is already your email.").into_response();
+    }
+
+    let ip = get_client_ip(&headers, remote_addr);
+    if is_sender_blocked(&state.db, Some(user.uid), &email, &ip).await {
+        return (StatusCode::FORBIDDEN, "This email cannot be used.").into_response();
+    }
+
+    let is_taken = sqlx::query!("SELECT uid FROM users WHERE lower(email) = $1", email)
+        .fetch_optional(&state.db)
+        .await
+        .unwrap()
+        .is_some();
+
+    if is_taken {
+        return (StatusCode::CONFLICT, "This email is already in use.").into_response();
+    }
+
+    let response = format!("Please open the link sent to {email} to confirm your new email.");
+
+    let ip_prefix = get_ip_prefix(&ip);
+    tokio::spawn(async move {
+        let link_result = send_email_change_link(user.uid, &user.name, &user.email, &email).await;
+        let notice_result =
+            send_email_change_requested_notice(&user.name, &user.email, &email, &ip_prefix).await;
+
+        if let Err(ref err) = link_result {
+            eprintln!("Email change handler failed to send confirmation link: {err:?}");
+        }
+        if let Err(ref err) = notice_result {
+            eprintln!("Email change handler failed to notify current address: {err:?}");
+        }
+    });
+
+    (StatusCode::ACCEPTED, response).into_response()
+}
+
+pub async fn handle_email_change_confirm(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Json(payload): Json<EmailChangeConfirmRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    if payload.expires <= chrono::Utc::now().timestamp() {
+        return (
+            StatusCode::GONE,
+            "This link has expired. Please change your email again.",
+        )
+            .into_response();
+    }
+
+    let Some(user) = sqlx::query!(
+        "SELECT name, email FROM users WHERE uid = $1 AND status = 'active'",
+        payload.uid
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap() else {
+        return (StatusCode::NOT_FOUND, "User not found.").into_response();
+    };
+
+    if !check_hash(
+        &email_change_payload(payload.uid, &user.email, &payload.email, payload.expires),
+        &payload.hash,
+        &MID_HASH_KEY,
+    ) {
+        return (StatusCode::FORBIDDEN, "Invalid or outdated link.").into_response();
+    }
+
+    // Only changes the address the link was issued for, in case it changed since
+    let updated = sqlx::query!(
+        "UPDATE users SET email = $1 WHERE uid = $2 AND email = $3",
+        payload.email,
+        payload.uid,
+        user.email
+    )
+    .execute(&state.db)
+    .await;
+
+    match updated {
+        Ok(result) if result.rows_affected() > 0 => {}
+        Ok(_) => return (StatusCode::FORBIDDEN, "Invalid or outdated link.").into_response(),
+        Err(_) => {
+            return (StatusCode::CONFLICT, "This email is already in use.").into_response();
+        }
+    }
+
+    let new_email = payload.email.clone();
+    tokio::spawn(async move {
+        let _ = send_email_changed_notice(&user.name, &user.email, &new_email).await;
+    });
+
+    (
+        StatusCode::OK,
+        format!("Your email has been changed to {}.", payload.email),
+    )
+        .into_response()
+}
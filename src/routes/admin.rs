@@ -0,0 +1,302 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
+use axum_csrf::CsrfToken;
+use serde::Deserialize;
+
+use crate::admin::{delete_user, list_users, set_user_blocked, set_user_priorities, set_user_role};
+use crate::blocklist::{RuleAction, RuleKind, add_rule, delete_rule, list_rules};
+use crate::constants::ADMIN_TOKEN;
+use crate::roles::get_role;
+use crate::routes::form::MessagePriority;
+use crate::session::get_session_user;
+use crate::state::AppState;
+use crate::utils::hash_token;
+
+#[derive(Deserialize)]
+pub struct AdminUsersQuery {
+    #[serde(default)]
+    search: String,
+}
+
+#[derive(Deserialize)]
+pub struct AdminRoleRequest {
+    csrf_token: Option<String>,
+    uid: i32,
+    role: i32,
+}
+
+#[derive(Deserialize)]
+pub struct AdminPrioritiesRequest {
+    csrf_token: Option<String>,
+    uid: i32,
+    // Resets the user to their role's allowed priorities if null
+    priorities: Option<Vec<MessagePriority>>,
+}
+
+#[derive(Deserialize)]
+pub struct AdminBlockRequest {
+    csrf_token: Option<String>,
+    uid: i32,
+    blocked: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AdminDeleteRequest {
+    csrf_token: Option<String>,
+    uid: i32,
+}
+
+#[derive(Deserialize)]
+pub struct AdminRuleAddRequest {
+    csrf_token: Option<String>,
+    kind: RuleKind,
+    value: String,
+    action: RuleAction,
+    #[serde(default)]
+    note: String,
+}
+
+#[derive(Deserialize)]
+pub struct AdminRuleDeleteRequest {
+    csrf_token: Option<String>,
+    id: i32,
+}
+
+#[derive(PartialEq)]
+enum AdminAuth {
+    Token,
+    Owner,
+}
+
+// Admin requests are authorised either by the admin token as a bearer token, or by the session of a user whose role is admin
+async fn get_admin_auth(state: &AppState, headers: &HeaderMap) -> Option<AdminAuth> {
+    if let Some(provided) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        // Digests are compared so that the comparison does not leak the token
+        let expected = ADMIN_TOKEN.as_deref()?;
+        return (hash_token(provided) == hash_token(expected)).then_some(AdminAuth::Token);
+    }
+
+    let user = get_session_user(state, headers).await?;
+    let role = get_role(&state.db, user.role).await;
+
+    (user.verified && role.admin).then_some(AdminAuth::Owner)
+}
+
+// Only requests using the owner's session cookie need a CSRF token
+fn is_csrf_valid(auth: &AdminAuth, token: &CsrfToken, csrf_token: Option<&str>) -> bool {
+    *auth == AdminAuth::Token || token.verify(csrf_token.unwrap_or_default()).is_ok()
+}
+
+pub async fn handle_admin_users(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AdminUsersQuery>,
+) -> impl IntoResponse {
+    if get_admin_auth(&state, &headers).await.is_none() {
+        return (StatusCode::UNAUTHORIZED, "Admin access required.").into_response();
+    }
+
+    let users = list_users(&state.db, query.search.trim()).await.unwrap();
+
+    Json(users).into_response()
+}
+
+pub async fn handle_admin_role(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<AdminRoleRequest>,
+) -> impl IntoResponse {
+    let Some(auth) = get_admin_auth(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Admin access required.").into_response();
+    };
+
+    // Validate csrf token
+    if !is_csrf_valid(&auth, &token, payload.csrf_token.as_deref()) {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    if set_user_role(&state.db, payload.uid, payload.role)
+        .await
+        .unwrap()
+    {
+        (StatusCode::OK, "Role updated.").into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "User or role not found.").into_response()
+    }
+}
+
+pub async fn handle_admin_priorities(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<AdminPrioritiesRequest>,
+) -> impl IntoResponse {
+    let Some(auth) = get_admin_auth(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Admin access required.").into_response();
+    };
+
+    // Validate csrf token
+    if !is_csrf_valid(&auth, &token, payload.csrf_token.as_deref()) {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let priorities = payload
+        .priorities
+        .map(|p| p.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+
+    if set_user_priorities(&state.db, payload.uid, priorities.as_deref())
+        .await
+        .unwrap()
+    {
+        (StatusCode::OK, "Allowed priorities updated.").into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "User not found.").into_response()
+    }
+}
+
+pub async fn handle_admin_block(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<AdminBlockRequest>,
+) -> impl IntoResponse {
+    let Some(auth) = get_admin_auth(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Admin access required.").into_response();
+    };
+
+    // Validate csrf token
+    if !is_csrf_valid(&auth, &token, payload.csrf_token.as_deref()) {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    if set_user_blocked(&state.db, payload.uid, payload.blocked)
+        .await
+        .unwrap()
+    {
+        let msg = if payload.blocked {
+            "User blocked."
+        } else {
+            "User unblocked."
+        };
+        (StatusCode::OK, msg).into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "User not found.").into_response()
+    }
+}
+
+pub async fn handle_admin_delete(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<AdminDeleteRequest>,
+) -> impl IntoResponse {
+    let Some(auth) = get_admin_auth(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Admin access required.").into_response();
+    };
+
+    // Validate csrf token
+    if !is_csrf_valid(&auth, &token, payload.csrf_token.as_deref()) {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    if delete_user(&state.db, payload.uid).await.unwrap() {
+        (StatusCode::OK, "User deleted.").into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "User not found.").into_response()
+    }
+}
+
+pub async fn handle_admin_rules(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if get_admin_auth(&state, &headers).await.is_none() {
+        return (StatusCode::UNAUTHORIZED, "Admin access required.").into_response();
+    }
+
+    let rules = list_rules(&state.db).await.unwrap();
+
+    Json(rules).into_response()
+}
+
+pub async fn handle_admin_rule_add(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<AdminRuleAddRequest>,
+) -> impl IntoResponse {
+    let Some(auth) = get_admin_auth(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Admin access required.").into_response();
+    };
+
+    // Validate csrf token
+    if !is_csrf_valid(&auth, &token, payload.csrf_token.as_deref()) {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let Some(value) = payload.kind.normalise(&payload.value) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid {} rule value.", payload.kind.as_str()),
+        )
+            .into_response();
+    };
+
+    let id = add_rule(
+        &state.db,
+        payload.kind,
+        &value,
+        payload.action,
+        payload.note.trim(),
+    )
+    .await
+    .unwrap();
+
+    (StatusCode::OK, format!("Rule {id} saved.")).into_response()
+}
+
+pub async fn handle_admin_rule_delete(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<AdminRuleDeleteRequest>,
+) -> impl IntoResponse {
+    let Some(auth) = get_admin_auth(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Admin access required.").into_response();
+    };
+
+    // Validate csrf token
+    if !is_csrf_valid(&auth, &token, payload.csrf_token.as_deref()) {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    if delete_rule(&state.db, payload.id).await.unwrap() {
+        (StatusCode::OK, "Rule deleted.").into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Rule not found.").into_response()
+    }
+}
@@ -14,22 +14,33 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use askama::Template;
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
 use axum_csrf::CsrfToken;
-use reqwest::Client;
 use serde::Deserialize;
+use std::net::SocketAddr;
 
+use crate::blocklist::is_sender_blocked;
+use crate::captcha::verify_captcha;
 use crate::constants::{
     ALLOW_MODIFY_DB, CARGO_PKG_VERSION, FROM_STANDARD, HOMEPAGE_URL, NOTIFICATION_EMAIL,
-    RECAPTCHA_SECRET_KEY,
+    ONE_TIME_LINK_LIFETIME, ONE_TIME_LOGIN_LINKS,
 };
+use crate::routes::approval::send_application_notice;
+use crate::routes::invite::redeem_invite;
 use crate::state::AppState;
-use crate::utils::{generate_random_token, send_email};
+use crate::utils::{generate_random_token, get_client_ip, hash_token, normalise_email, send_email};
 
 #[derive(Template)]
 #[template(path = "email_link.html")]
 struct LinkEmailTemplate<'a> {
     link: &'a str,
+    one_time: bool,
+    valid_minutes: i64,
     version: &'a str,
 }
 
@@ -38,12 +49,30 @@ pub struct ApplyRequest {
     csrf_token: String,
     email: String,
     name: String,
-    recaptcha: String,
+    #[serde(default, alias = "recaptcha")]
+    captcha: String,
+    // Invite code from an owner-issued invite link
+    invite: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct RecaptchaResponse {
-    success: bool,
+// Stores a new login token for the user, returning the plaintext token to be emailed.
+//    With one-time login links, the token is single-use and expires shortly
+pub async fn issue_login_token(state: &AppState, uid: i32) -> String {
+    let token = generate_random_token();
+    let one_time = *ONE_TIME_LOGIN_LINKS;
+
+    sqlx::query!(
+        "INSERT INTO login_tokens (user_uid, token_hash, single_use, expires_time) VALUES ($1, $2, $3, $4)",
+        uid,
+        hash_token(&token),
+        one_time,
+        one_time.then(|| chrono::Utc::now() + ONE_TIME_LINK_LIFETIME)
+    )
+    .execute(&state.db)
+    .await
+    .unwrap();
+
+    token
 }
 
 pub async fn send_login_link(name: &str, email: &str, token: &str) -> anyhow::Result<()> {
@@ -53,6 +82,8 @@ pub async fn send_login_link(name: &str, email: &str, token: &str) -> anyhow::Re
     let link = format!("{}?token={}", *HOMEPAGE_URL, token);
     let link_template = LinkEmailTemplate {
         link: &link,
+        one_time: *ONE_TIME_LOGIN_LINKS,
+        valid_minutes: ONE_TIME_LINK_LIFETIME.num_minutes(),
         version: CARGO_PKG_VERSION,
     };
     let link_body = link_template
@@ -69,9 +100,20 @@ pub async fn send_login_link(name: &str, email: &str, token: &str) -> anyhow::Re
     .await
 }
 
+// Describes the emailed link in responses, which is only permanent without one-time login links
+pub fn login_link_description() -> &'static str {
+    if *ONE_TIME_LOGIN_LINKS {
+        "one-time login link"
+    } else {
+        "permanent login link"
+    }
+}
+
 pub async fn handle_apply(
     State(state): State<AppState>,
     token: CsrfToken,
+    headers: HeaderMap,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
     Json(payload): Json<ApplyRequest>,
 ) -> impl IntoResponse {
     // If not prod or beta, do not modify database. See constants
@@ -88,72 +130,59 @@ pub async fn handle_apply(
         return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
     }
 
-    if payload.recaptcha.is_empty() {
-        return (StatusCode::BAD_REQUEST, "reCAPTCHA verification failed").into_response();
-    }
+    let Some(email) = normalise_email(&payload.email) else {
+        return (StatusCode::BAD_REQUEST, "Email invalid.").into_response();
+    };
 
-    let client = Client::new();
-    let params = [
-        ("secret", RECAPTCHA_SECRET_KEY.as_str()),
-        ("response", &payload.recaptcha),
-    ];
-
-    let res = client
-        .post("https://www.google.com/recaptcha/api/siteverify")
-        .form(&params)
-        .send()
-        .await;
-
-    match res {
-        Ok(response) => {
-            if let Ok(recaptcha_response) = response.json::<RecaptchaResponse>().await
-                && recaptcha_response.success
-            {
-                let token = generate_random_token();
-
-                match sqlx::query!(
-                    "INSERT INTO users (email, name, token, verified, role) VALUES ($1, $2, $3, $4, $5)",
-                    payload.email.trim(),
-                    payload.name.trim(),
-                    token,
-                    false,
-                    0
-                )
-                .execute(&state.db)
-                .await
-                {
-                    Ok(_) => (),
-                    Err(_) => {
-                        return (StatusCode::BAD_REQUEST, "Duplicate Email").into_response();
-                    }
-                }
-
-                tokio::spawn(async move {
-                    let _ =
-                        send_login_link(payload.name.trim(), payload.email.trim(), &token).await;
-                });
-
-                /* if let Err(ref err) = link_result {
-                    eprintln!("Application handler failed to send login link: {:?}", err);
-                    return (
-                        StatusCode::CREATED,
-                        "Registration successful, but login link email failed to send. Please try again later.",
-                    )
-                        .into_response();
-                } */
-
-                return (
-                    StatusCode::CREATED,
-                    "Please check your email for your permanent login link.",
-                )
-                    .into_response();
-            }
-            (StatusCode::BAD_REQUEST, "reCAPTCHA verification failed").into_response()
-        }
-        Err(_) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Error verifying reCAPTCHA",
+    let ip = get_client_ip(&headers, remote_addr);
+    if is_sender_blocked(&state.db, None, &email, &ip).await {
+        return (
+            StatusCode::FORBIDDEN,
+            "Applications from this address are not accepted.",
         )
-            .into_response(),
+            .into_response();
     }
+
+    if let Some(code) = payload.invite.as_deref().filter(|c| !c.is_empty()) {
+        return redeem_invite(&state, code, payload.name.trim(), &email).await;
+    }
+
+    if let Err(response) = verify_captcha(state.captcha.as_ref(), &payload.captcha, &ip).await {
+        return response;
+    }
+
+    // New accounts cannot log in until the owner approves them
+    let uid = match sqlx::query!(
+        "INSERT INTO users (email, name, verified, role, status) VALUES ($1, $2, $3, $4, $5) RETURNING uid",
+        email,
+        payload.name.trim(),
+        false,
+        0,
+        "pending_approval"
+    )
+    .fetch_one(&state.db)
+    .await
+    {
+        Ok(rec) => rec.uid,
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, "Duplicate Email").into_response();
+        }
+    };
+
+    tokio::spawn(async move {
+        let notice_result = send_application_notice(uid, payload.name.trim(), &email).await;
+
+        if let Err(ref err) = notice_result {
+            eprintln!("Application handler failed to send application notice: {err:?}");
+        }
+    });
+
+    (
+        StatusCode::CREATED,
+        format!(
+            "Your application has been received. Once it is approved, you will receive your {} by email.",
+            login_link_description()
+        ),
+    )
+        .into_response()
 }
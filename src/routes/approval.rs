@@ -0,0 +1,190 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use askama::Template;
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use axum_csrf::CsrfToken;
+use serde::Deserialize;
+
+use crate::constants::{
+    CARGO_PKG_VERSION, FROM_STANDARD, HOMEPAGE_URL, MID_HASH_KEY, NOTIFICATION_EMAIL,
+};
+use crate::routes::apply::{issue_login_token, send_login_link};
+use crate::state::AppState;
+use crate::utils::{check_hash, generate_hash, send_email};
+
+#[derive(Template)]
+#[template(path = "email_application.html")]
+struct ApplicationEmailTemplate<'a> {
+    name: &'a str,
+    email: &'a str,
+    approve_link: &'a str,
+    reject_link: &'a str,
+    version: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email_rejected.html")]
+struct RejectedEmailTemplate<'a> {
+    name: &'a str,
+    version: &'a str,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ApplicationDecision {
+    Approve,
+    Reject,
+}
+
+impl std::fmt::Display for ApplicationDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplicationDecision::Approve => write!(f, "approve"),
+            ApplicationDecision::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ApplicationDecisionRequest {
+    csrf_token: String,
+    uid: i32,
+    decision: ApplicationDecision,
+    hash: String,
+}
+
+// Each link is signed for one user and one decision, so an approve link cannot be turned into a reject link
+fn decision_link(uid: i32, decision: ApplicationDecision) -> String {
+    let hash = generate_hash(&format!("{decision}:{uid}"), &MID_HASH_KEY);
+    format!(
+        "{}application?uid={uid}&decision={decision}&hash={hash}",
+        *HOMEPAGE_URL
+    )
+}
+
+// Emails the owner about a new application, with signed links to approve or reject it
+pub async fn send_application_notice(uid: i32, name: &str, email: &str) -> anyhow::Result<()> {
+    let subject = format!("[Enviame] Account application from {name}");
+    let approve_link = decision_link(uid, ApplicationDecision::Approve);
+    let reject_link = decision_link(uid, ApplicationDecision::Reject);
+    let application_template = ApplicationEmailTemplate {
+        name,
+        email,
+        approve_link: &approve_link,
+        reject_link: &reject_link,
+        version: CARGO_PKG_VERSION,
+    };
+    let application_body = application_template
+        .render()
+        .expect("Application email failed to render");
+
+    send_email(
+        &FROM_STANDARD,
+        &NOTIFICATION_EMAIL,
+        email,
+        &subject,
+        &application_body,
+    )
+    .await
+}
+
+async fn send_rejection_notice(name: &str, email: &str) -> anyhow::Result<()> {
+    let subject = "[Enviame] Account application declined";
+    let rejected_template = RejectedEmailTemplate {
+        name,
+        version: CARGO_PKG_VERSION,
+    };
+    let rejected_body = rejected_template
+        .render()
+        .expect("Rejection email failed to render");
+
+    send_email(
+        &FROM_STANDARD,
+        email,
+        &NOTIFICATION_EMAIL,
+        subject,
+        &rejected_body,
+    )
+    .await
+}
+
+pub async fn handle_application_decision(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Json(payload): Json<ApplicationDecisionRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let decision = payload.decision;
+    if !check_hash(
+        &format!("{decision}:{}", payload.uid),
+        &payload.hash,
+        &MID_HASH_KEY,
+    ) {
+        return (StatusCode::FORBIDDEN, "Invalid approval link.").into_response();
+    }
+
+    let new_status = match decision {
+        ApplicationDecision::Approve => "active",
+        ApplicationDecision::Reject => "rejected",
+    };
+
+    // Only pending applications can be decided, so each one is only acted on once
+    let Some(user) = sqlx::query!(
+        "UPDATE users SET status = $1 WHERE uid = $2 AND status = 'pending_approval' RETURNING name, email",
+        new_status,
+        payload.uid
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap() else {
+        return (
+            StatusCode::CONFLICT,
+            "This application has already been reviewed.",
+        )
+            .into_response();
+    };
+
+    match decision {
+        ApplicationDecision::Approve => {
+            let token = issue_login_token(&state, payload.uid).await;
+
+            tokio::spawn(async move {
+                let _ = send_login_link(&user.name, &user.email, &token).await;
+            });
+
+            (
+                StatusCode::OK,
+                "Application approved. The applicant has been sent their login link.",
+            )
+                .into_response()
+        }
+        ApplicationDecision::Reject => {
+            tokio::spawn(async move {
+                let _ = send_rejection_notice(&user.name, &user.email).await;
+            });
+
+            (
+                StatusCode::OK,
+                "Application rejected. The applicant has been notified.",
+            )
+                .into_response()
+        }
+    }
+}
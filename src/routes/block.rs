@@ -0,0 +1,87 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use axum_csrf::CsrfToken;
+use serde::Deserialize;
+
+use crate::blocklist::{RuleAction, RuleKind, add_rule};
+use crate::constants::MID_HASH_KEY;
+use crate::state::AppState;
+use crate::utils::check_hash;
+
+#[derive(Deserialize)]
+pub struct BlockSenderRequest {
+    csrf_token: String,
+    mid: i32,
+    hash: String,
+}
+
+// Blocks the email address that sent a message, and the user if they have an account, and cancels their queued messages
+pub async fn handle_block_sender(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Json(payload): Json<BlockSenderRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    if !check_hash(
+        &format!("block:{}", payload.mid),
+        &payload.hash,
+        &MID_HASH_KEY,
+    ) {
+        return (StatusCode::FORBIDDEN, "Invalid block link.").into_response();
+    }
+
+    let Some(msg) = sqlx::query!(
+        "SELECT m.user_uid, COALESCE(u.email, m.email) AS \"email!\" FROM messages m LEFT JOIN users u ON u.uid = m.user_uid WHERE m.id = $1",
+        payload.mid
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap() else {
+        return (StatusCode::NOT_FOUND, "Message not found.").into_response();
+    };
+
+    // Account holders are blocked by their email too, so they cannot keep writing as guests
+    let mut rules = vec![(RuleKind::Email, msg.email.trim().to_lowercase())];
+    if let Some(uid) = msg.user_uid {
+        rules.push((RuleKind::Uid, uid.to_string()));
+    }
+    let note = format!("Blocked from message {}", payload.mid);
+    for (kind, value) in rules {
+        add_rule(&state.db, kind, &value, RuleAction::Block, &note)
+            .await
+            .unwrap();
+    }
+
+    sqlx::query!(
+        "UPDATE messages SET status = 'cancelled' WHERE status IN ('unconfirmed', 'scheduled', 'pending') AND (user_uid = $1 OR (user_uid IS NULL AND lower(email) = lower($2)))",
+        msg.user_uid,
+        msg.email
+    )
+    .execute(&state.db)
+    .await
+    .unwrap();
+
+    (
+        StatusCode::OK,
+        "Sender blocked. Their queued messages have been cancelled.",
+    )
+        .into_response()
+}
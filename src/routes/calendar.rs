@@ -16,20 +16,42 @@
 use axum::{Json, extract::State, response::IntoResponse};
 use serde::Serialize;
 
+use crate::constants::CALENDAR_DATETIME_FORMAT;
+use crate::roles::PRIORITIES;
 use crate::state::AppState;
 
+#[derive(Serialize)]
+struct ProjectedDelivery {
+    standard: String,
+    urgent: String,
+    immediate: String,
+}
+
 #[derive(Serialize)]
 struct CalendarResponse {
     is_busy: bool,
     timestamp: String,
+    projected: ProjectedDelivery,
 }
 
 pub async fn handle_calendar_status_query(State(state): State<AppState>) -> impl IntoResponse {
     let calendar_cache = state.status.read().await;
 
+    let projected = |priority: &str| {
+        calendar_cache
+            .projected_delivery(priority, None, None, &PRIORITIES, false)
+            .format(CALENDAR_DATETIME_FORMAT)
+            .to_string()
+    };
+
     Json(CalendarResponse {
         is_busy: calendar_cache.is_busy,
         timestamp: calendar_cache.timestamp.clone(),
+        projected: ProjectedDelivery {
+            standard: projected("standard"),
+            urgent: projected("urgent"),
+            immediate: projected("immediate"),
+        },
     })
     .into_response()
 }
@@ -0,0 +1,26 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+
+use crate::state::AppState;
+
+// Issues a challenge to the proof-of-work widget
+pub async fn handle_captcha_challenge(State(state): State<AppState>) -> impl IntoResponse {
+    match state.captcha.challenge() {
+        Some(challenge) => Json(challenge).into_response(),
+        None => (StatusCode::NOT_FOUND, "No CAPTCHA challenge to solve.").into_response(),
+    }
+}
@@ -17,48 +17,130 @@ use axum::{
     Json,
     extract::{ConnectInfo, State},
     http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 use axum_csrf::CsrfToken;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 use std::net::SocketAddr;
 
-use crate::constants::{ALLOW_MODIFY_DB, MID_HASH_KEY};
+use crate::blocklist::is_sender_blocked;
+use crate::constants::{ALLOW_MODIFY_DB, CALENDAR_DATETIME_FORMAT, GUEST_PRIORITIES, MID_HASH_KEY};
+use crate::roles::{PRIORITIES, Role, budget_period, get_role, remaining_budget};
+use crate::routes::guest::issue_guest_code;
+use crate::session::get_session_user;
 use crate::state::AppState;
-use crate::utils::generate_hash;
+use crate::utils::{generate_hash, get_client_ip, get_user_agent};
 
 #[derive(Deserialize)]
 #[serde(rename_all = "lowercase")]
-enum MessagePriority {
+pub enum MessagePriority {
     Standard,
     Urgent,
     Immediate,
 }
 
-impl std::fmt::Display for MessagePriority {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl MessagePriority {
+    pub fn as_str(&self) -> &'static str {
         match self {
-            MessagePriority::Standard => write!(f, "standard"),
-            MessagePriority::Urgent => write!(f, "urgent"),
-            MessagePriority::Immediate => write!(f, "immediate"),
+            MessagePriority::Standard => "standard",
+            MessagePriority::Urgent => "urgent",
+            MessagePriority::Immediate => "immediate",
         }
     }
 }
 
+impl std::fmt::Display for MessagePriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 #[derive(Deserialize)]
 pub struct FormData {
     csrf_token: String,
-    token: Option<String>,
     email: String,
     name: String,
     message: String,
     priority: MessagePriority,
+    deliver_after: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    needed_by: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize)]
 struct SubmissionResponse {
     mid: i32,
     mid_hash: String,
+    status: &'static str,
+    // Lower than requested if the message was downgraded for being over budget
+    priority: &'static str,
+    projected_delivery: String,
+}
+
+// Messages without a sender account, from guests or deleted users, can only use the guest priorities
+pub fn guest_priority_error(priority: &str) -> Option<Response> {
+    if GUEST_PRIORITIES.contains(&priority) {
+        return None;
+    }
+
+    let msg = match GUEST_PRIORITIES.last() {
+        Some(highest) => format!(
+            "Guests cannot send {priority} messages. The highest priority guests can use is {highest}, or apply for an account below."
+        ),
+        None => "Guests cannot send messages. Apply for an account below.".to_owned(),
+    };
+    Some((StatusCode::FORBIDDEN, msg).into_response())
+}
+
+// Priority a user's message is sent with, used for both new and edited messages.
+//    Rejects priorities the user may not use, and messages over the user's weekly budget
+//    unless their role downgrades them to a lower priority still in budget
+pub async fn resolve_priority(
+    conn: &mut PgConnection,
+    uid: i32,
+    role: &Role,
+    user_priorities: Option<&[String]>,
+    requested: &str,
+    editing: Option<i32>,
+) -> Result<&'static str, Response> {
+    let allowed = role.allowed_priorities_for(user_priorities);
+
+    if !allowed.contains(&requested) {
+        let msg = match allowed.last() {
+            Some(highest) => format!(
+                "You cannot send {requested} messages. The highest priority you can use is {highest}."
+            ),
+            None => "You cannot send messages.".to_owned(),
+        };
+        return Err((StatusCode::FORBIDDEN, msg).into_response());
+    }
+
+    let requested_level = PRIORITIES.iter().position(|p| *p == requested);
+    for priority in allowed
+        .into_iter()
+        .rev()
+        .filter(|p| PRIORITIES.iter().position(|q| q == p) <= requested_level)
+    {
+        if remaining_budget(&mut *conn, role, uid, priority, editing).await != Some(0) {
+            return Ok(priority);
+        }
+        if !role.downgrade_over_budget {
+            break;
+        }
+    }
+
+    let (_, refills_at) = budget_period();
+    Err((
+        StatusCode::TOO_MANY_REQUESTS,
+        format!(
+            "You have used your weekly budget of {} {requested} messages. It refills at {} UTC.",
+            role.weekly_budget(requested).unwrap_or_default(),
+            refills_at.format(CALENDAR_DATETIME_FORMAT)
+        ),
+    )
+        .into_response())
 }
 
 pub async fn handle_form_submission(
@@ -82,68 +164,183 @@ pub async fn handle_form_submission(
         return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
     }
 
-    let user = match payload.token {
-        Some(ref token) => sqlx::query!(
-            "SELECT uid, verified, role FROM users WHERE token = $1",
-            token
-        )
-        .fetch_optional(&state.db)
-        .await
-        .unwrap(),
-        None => None,
-    };
+    if payload.message.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "Message is required.").into_response();
+    }
 
-    // Disallow guests (temporarily)
-    if user.is_none() {
+    let user = get_session_user(&state, &headers).await;
+    let ip = get_client_ip(&headers, remote_addr);
+
+    // Signed in users are matched by their account email rather than the one in the form
+    let email = user
+        .as_ref()
+        .map_or(payload.email.as_str(), |u| u.email.as_str());
+    if is_sender_blocked(&state.db, user.as_ref().map(|u| u.uid), email, &ip).await {
         return (
             StatusCode::FORBIDDEN,
-            "Guests are currently not allowed to send messages. Apply for an account below.",
+            "You are not allowed to send messages.",
         )
             .into_response();
     }
 
-    let sender_status = match user {
-        Some(ref u) if u.verified && u.role == 1 => "trusted",
-        Some(ref u) if u.verified => "verified",
-        Some(_) => "unverified",
-        None => "guest",
+    let role = match user {
+        Some(ref u) => Some(get_role(&state.db, u.role).await),
+        None => None,
     };
 
-    let user_agent = headers
-        .get("user-agent")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("")
-        .to_string();
+    // Budgets and quotas are checked and used up in one transaction, holding a lock on the sender
+    let mut tx = state.db.begin().await.unwrap();
+    if let Some(ref u) = user {
+        sqlx::query!("SELECT uid FROM users WHERE uid = $1 FOR UPDATE", u.uid)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+    }
+
+    let mut priority = payload.priority.as_str();
+    if let (Some(u), Some(r)) = (&user, &role) {
+        priority = match resolve_priority(
+            &mut tx,
+            u.uid,
+            r,
+            u.allowed_priorities.as_deref(),
+            priority,
+            None,
+        )
+        .await
+        {
+            Ok(priority) => priority,
+            Err(response) => return response,
+        };
+    } else if let Some(response) = guest_priority_error(priority) {
+        return response;
+    }
+
+    if let Some(quota) = role.as_ref().and_then(|r| r.daily_quota) {
+        let sent_today = sqlx::query!(
+            "SELECT COUNT(*) AS \"count!\" FROM messages WHERE user_uid = $1 AND status <> 'cancelled' AND submitted_time > now() - INTERVAL '24 hours'",
+            user.as_ref().map(|u| u.uid)
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap()
+        .count;
+
+        if sent_today >= quota as i64 {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("You can send at most {quota} messages per day."),
+            )
+                .into_response();
+        }
+    }
+
+    // Verified users are shown by their role name
+    let sender_status = match (&user, &role) {
+        (Some(u), Some(r)) if u.verified => r.name.as_str(),
+        (Some(_), _) => "unverified",
+        (None, _) => "guest",
+    };
+    let bypass_sleep = role.as_ref().is_some_and(|r| r.bypass_sleep);
+    // The email worker only escalates messages to priorities their sender can send and has budget for
+    let mut escalates_to = GUEST_PRIORITIES.clone();
+    if let (Some(u), Some(r)) = (&user, &role) {
+        escalates_to.clear();
+        for p in r.allowed_priorities_for(u.allowed_priorities.as_deref()) {
+            if remaining_budget(&mut *tx, r, u.uid, p, None).await != Some(0) {
+                escalates_to.push(p);
+            }
+        }
+    }
+
+    let user_agent = get_user_agent(&headers);
 
-    let ip = headers
-        .get("x-forwarded-for")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .map(|s| s.trim().to_string())
-        .unwrap_or_else(|| remote_addr.ip().to_string());
+    // Messages with a future delivery time are held by the email worker until then
+    let deliver_after = payload.deliver_after.filter(|t| *t > Utc::now());
+
+    if let Some(expires_at) = payload.expires_at {
+        if expires_at <= Utc::now() {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Expiry time must be in the future.",
+            )
+                .into_response();
+        }
+        if deliver_after.is_some_and(|t| t >= expires_at) {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Expiry time must be after the delivery time.",
+            )
+                .into_response();
+        }
+    }
+    // Guest messages only enter the queue once the guest confirms their email address
+    let status = if user.is_none() {
+        "unconfirmed"
+    } else if deliver_after.is_some() {
+        "scheduled"
+    } else {
+        "pending"
+    };
+
+    if payload.needed_by.is_some_and(|t| t <= Utc::now()) {
+        return (StatusCode::BAD_REQUEST, "Deadline must be in the future.").into_response();
+    }
 
     let message_id = sqlx::query!(
-        "INSERT INTO messages (status, user_uid, sender, name, email, message, priority, ua, ip) VALUES ('pending', $1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+        "INSERT INTO messages (status, user_uid, sender, name, email, message, priority, ua, ip, deliver_after, expires_at, needed_by, submitted_priority) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $7) RETURNING id",
+        status,
         user.as_ref().map(|u| u.uid),
         sender_status,
         payload.name.trim(),
         payload.email.trim(),
         payload.message.trim(),
-        payload.priority.to_string(),
+        priority,
         user_agent,
-        ip
+        ip,
+        deliver_after,
+        payload.expires_at,
+        payload.needed_by
     )
-        .fetch_one(&state.db)
+        .fetch_one(&mut *tx)
         .await
         .expect("Failed to insert data")
         .id;
+    tx.commit().await.unwrap();
     let mid_hash = generate_hash(&message_id.to_string(), &MID_HASH_KEY);
 
+    if user.is_none() {
+        issue_guest_code(
+            &state,
+            message_id,
+            payload.name.trim(),
+            payload.email.trim(),
+        )
+        .await;
+    }
+
+    let projected_delivery = state
+        .status
+        .read()
+        .await
+        .projected_delivery(
+            priority,
+            deliver_after,
+            payload.needed_by,
+            &escalates_to,
+            bypass_sleep,
+        )
+        .format(CALENDAR_DATETIME_FORMAT)
+        .to_string();
+
     (
         StatusCode::ACCEPTED,
         Json(SubmissionResponse {
             mid: message_id,
             mid_hash,
+            status,
+            priority,
+            projected_delivery,
         }),
     )
         .into_response()
@@ -0,0 +1,155 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use askama::Template;
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use axum_csrf::CsrfToken;
+use serde::{Deserialize, Serialize};
+
+use crate::constants::{
+    CARGO_PKG_VERSION, FROM_STANDARD, GUEST_CODE_ATTEMPTS, GUEST_CODE_LIFETIME, MID_HASH_KEY,
+    NOTIFICATION_EMAIL,
+};
+use crate::state::AppState;
+use crate::utils::{check_hash, generate_hash, generate_numeric_code, send_email};
+
+#[derive(Template)]
+#[template(path = "email_guest_code.html")]
+struct GuestCodeEmailTemplate<'a> {
+    code: &'a str,
+    valid_minutes: i64,
+    version: &'a str,
+}
+
+#[derive(Deserialize)]
+pub struct MessageConfirmRequest {
+    csrf_token: String,
+    mid: i32,
+    mid_hash: String,
+    code: String,
+}
+
+#[derive(Serialize)]
+struct MessageConfirmResponse {
+    mid: i32,
+    status: String,
+}
+
+// Codes are short, so they are signed together with the message id and only accepted a few times
+fn code_hash(mid: i32, code: &str) -> String {
+    generate_hash(&format!("{mid}:{code}"), &MID_HASH_KEY)
+}
+
+// Stores a confirmation code for a guest's message and emails it to the guest
+pub async fn issue_guest_code(state: &AppState, mid: i32, name: &str, email: &str) {
+    let code = generate_numeric_code(6);
+
+    sqlx::query!(
+        "INSERT INTO guest_verifications (message_id, code_hash, expires_time) VALUES ($1, $2, $3)",
+        mid,
+        code_hash(mid, &code),
+        chrono::Utc::now() + GUEST_CODE_LIFETIME
+    )
+    .execute(&state.db)
+    .await
+    .unwrap();
+
+    let subject = format!("[Enviame] Confirmation code for {name}");
+    let code_template = GuestCodeEmailTemplate {
+        code: &code,
+        valid_minutes: GUEST_CODE_LIFETIME.num_minutes(),
+        version: CARGO_PKG_VERSION,
+    };
+    let code_body = code_template
+        .render()
+        .expect("Guest code email failed to render");
+
+    let email = email.to_owned();
+    tokio::spawn(async move {
+        let code_result = send_email(
+            &FROM_STANDARD,
+            &email,
+            &NOTIFICATION_EMAIL,
+            &subject,
+            &code_body,
+        )
+        .await;
+
+        if let Err(ref err) = code_result {
+            eprintln!("Guest handler failed to send confirmation code: {err:?}");
+        }
+    });
+}
+
+pub async fn handle_message_confirm(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Json(payload): Json<MessageConfirmRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    if !check_hash(
+        &payload.mid.to_string(),
+        &payload.mid_hash,
+        MID_HASH_KEY.as_str(),
+    ) {
+        return (StatusCode::BAD_REQUEST, "Hash validation failed.").into_response();
+    }
+
+    // Every attempt is counted before the code is checked, so codes cannot be guessed
+    let Some(verification) = sqlx::query!(
+        "UPDATE guest_verifications SET attempts = attempts + 1 WHERE message_id = $1 AND expires_time > now() AND attempts < $2 RETURNING code_hash",
+        payload.mid,
+        GUEST_CODE_ATTEMPTS
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap() else {
+        return (
+            StatusCode::GONE,
+            "This code has expired or was entered wrongly too many times. Please submit your message again.",
+        )
+            .into_response();
+    };
+
+    if !check_hash(
+        &format!("{}:{}", payload.mid, payload.code.trim()),
+        &verification.code_hash,
+        MID_HASH_KEY.as_str(),
+    ) {
+        return (StatusCode::BAD_REQUEST, "Incorrect code.").into_response();
+    }
+
+    // The message enters the queue as if it had just been submitted
+    let rec = sqlx::query!(
+        "WITH confirmed AS (DELETE FROM guest_verifications WHERE message_id = $1 RETURNING message_id) UPDATE messages SET status = CASE WHEN deliver_after > now() THEN 'scheduled' ELSE 'pending' END WHERE id IN (SELECT message_id FROM confirmed) AND status = 'unconfirmed' RETURNING status",
+        payload.mid
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap();
+
+    match rec {
+        Some(rec) => Json(MessageConfirmResponse {
+            mid: payload.mid,
+            status: rec.status,
+        })
+        .into_response(),
+        None => (StatusCode::CONFLICT, "Message has already been confirmed.").into_response(),
+    }
+}
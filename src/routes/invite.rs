@@ -0,0 +1,92 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use crate::constants::HOMEPAGE_URL;
+use crate::routes::apply::{issue_login_token, login_link_description, send_login_link};
+use crate::state::AppState;
+use crate::utils::{generate_random_token, hash_token};
+
+// Stores a new invite, returning the link to be shared with the invitee.
+//    Without max_uses or expires_time, the invite can be redeemed any number of times
+pub async fn create_invite(
+    db: &PgPool,
+    role: i32,
+    max_uses: Option<i32>,
+    expires_time: Option<DateTime<Utc>>,
+) -> anyhow::Result<String> {
+    let code = generate_random_token();
+
+    sqlx::query!(
+        "INSERT INTO invites (code_hash, role, max_uses, expires_time) VALUES ($1, $2, $3, $4)",
+        hash_token(&code),
+        role,
+        max_uses,
+        expires_time
+    )
+    .execute(db)
+    .await?;
+
+    Ok(format!("{}apply?invite={code}", *HOMEPAGE_URL))
+}
+
+// Invited users skip CAPTCHA and approval, and are created verified with the invite's role
+pub async fn redeem_invite(state: &AppState, code: &str, name: &str, email: &str) -> Response {
+    // Using up the invite and creating the user happen in one statement,
+    //    so a failed registration does not count as a use
+    let uid = match sqlx::query!(
+        "WITH invite AS (UPDATE invites SET uses = uses + 1 WHERE code_hash = $1 AND (expires_time IS NULL OR expires_time > now()) AND (max_uses IS NULL OR uses < max_uses) RETURNING role) INSERT INTO users (email, name, verified, role, status) SELECT $2, $3, true, role, 'active' FROM invite RETURNING uid",
+        hash_token(code),
+        email,
+        name
+    )
+    .fetch_optional(&state.db)
+    .await
+    {
+        Ok(Some(rec)) => rec.uid,
+        Ok(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "This invite is invalid, expired or has been used up.",
+            )
+                .into_response();
+        }
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, "Duplicate Email").into_response();
+        }
+    };
+
+    let token = issue_login_token(state, uid).await;
+
+    let (name, email) = (name.to_owned(), email.to_owned());
+    tokio::spawn(async move {
+        let _ = send_login_link(&name, &email, &token).await;
+    });
+
+    (
+        StatusCode::CREATED,
+        format!(
+            "Please check your email for your {}.",
+            login_link_description()
+        ),
+    )
+        .into_response()
+}
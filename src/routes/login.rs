@@ -13,19 +13,47 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use askama::Template;
 use axum::{
     Json,
-    extract::{Query, State},
-    http::header,
-    response::IntoResponse,
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{AppendHeaders, IntoResponse},
 };
+use axum_csrf::CsrfToken;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
+use crate::constants::{
+    CALENDAR_DATETIME_FORMAT, CARGO_PKG_VERSION, EMAIL_DATETIME_FORMAT, FROM_STANDARD,
+    HOMEPAGE_URL, NOTIFICATION_EMAIL,
+};
+use crate::roles::{Role, budget_period, get_role, remaining_budget};
+use crate::session::{
+    CLEAR_LEGACY_TOKEN_COOKIE, CLEAR_SESSION_COOKIE, create_session, delete_session,
+    get_session_user, is_new_device, session_cookie,
+};
 use crate::state::AppState;
+use crate::utils::{get_client_ip, get_ip_prefix, get_user_agent, hash_token, send_email};
+
+#[derive(Template)]
+#[template(path = "email_new_device.html")]
+struct NewDeviceEmailTemplate<'a> {
+    login_time: &'a str,
+    user_agent: &'a str,
+    ip_prefix: &'a str,
+    link: &'a str,
+    version: &'a str,
+}
 
 #[derive(Deserialize)]
 pub struct LoginRequest {
-    token: String,
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LogoutRequest {
+    csrf_token: String,
 }
 
 #[derive(Serialize)]
@@ -34,43 +62,165 @@ struct LoginResponse {
     name: Option<String>,
     verified: Option<bool>,
     role: Option<i32>,
+    allowed_priorities: Option<Vec<&'static str>>,
+    badge: Option<String>,
+    budget: Option<BudgetResponse>,
+}
+
+#[derive(Serialize)]
+struct BudgetResponse {
+    // Messages left this week, null if unlimited
+    urgent: Option<i64>,
+    immediate: Option<i64>,
+    refills_at: String,
+}
+
+async fn get_budget(state: &AppState, uid: i32, role: &Role) -> BudgetResponse {
+    let (_, refills_at) = budget_period();
+
+    BudgetResponse {
+        urgent: remaining_budget(&state.db, role, uid, "urgent", None).await,
+        immediate: remaining_budget(&state.db, role, uid, "immediate", None).await,
+        refills_at: refills_at.format(CALENDAR_DATETIME_FORMAT).to_string(),
+    }
+}
+
+pub async fn send_new_device_alert(
+    name: &str,
+    email: &str,
+    user_agent: &str,
+    ip_prefix: &str,
+) -> anyhow::Result<()> {
+    let subject = format!("[Enviame] New login for {name}");
+    let login_time = chrono::Utc::now().format(EMAIL_DATETIME_FORMAT).to_string();
+    let link = format!("{}sessions", *HOMEPAGE_URL);
+    let alert_template = NewDeviceEmailTemplate {
+        login_time: &login_time,
+        user_agent,
+        ip_prefix,
+        link: &link,
+        version: CARGO_PKG_VERSION,
+    };
+    let alert_body = alert_template
+        .render()
+        .expect("New device email failed to render");
+
+    send_email(
+        &FROM_STANDARD,
+        email,
+        &NOTIFICATION_EMAIL,
+        &subject,
+        &alert_body,
+    )
+    .await
 }
 
 pub async fn handle_login(
     Query(params): Query<LoginRequest>,
     State(state): State<AppState>,
+    headers: HeaderMap,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
 ) -> impl IntoResponse {
+    // Without a login token, report the user of the current session
+    let Some(token) = params.token else {
+        return match get_session_user(&state, &headers).await {
+            Some(user) => {
+                let role = get_role(&state.db, user.role).await;
+                let budget = get_budget(&state, user.uid, &role).await;
+                Json(LoginResponse {
+                    email: Some(user.email),
+                    name: Some(user.name),
+                    verified: Some(user.verified),
+                    role: Some(user.role),
+                    allowed_priorities: Some(
+                        role.allowed_priorities_for(user.allowed_priorities.as_deref()),
+                    ),
+                    badge: Some(role.badge_colour),
+                    budget: Some(budget),
+                })
+                .into_response()
+            }
+            None => Json(LoginResponse {
+                email: None,
+                name: None,
+                verified: None,
+                role: None,
+                allowed_priorities: None,
+                badge: None,
+                budget: None,
+            })
+            .into_response(),
+        };
+    };
+
     let result = sqlx::query!(
-        "SELECT email, name, verified, role FROM users WHERE token = $1",
-        params.token
+        "SELECT t.id AS token_id, t.single_use, u.uid, u.email, u.name, u.verified, u.role, u.allowed_priorities FROM users u JOIN login_tokens t ON t.user_uid = u.uid WHERE t.token_hash = $1 AND u.status = 'active' AND (t.expires_time IS NULL OR t.expires_time > now())",
+        hash_token(&token)
     )
     .fetch_optional(&state.db)
     .await
     .unwrap();
 
+    // Single-use tokens are consumed here, only the request that deletes one may redeem it
+    let result = match result {
+        Some(user) if user.single_use => sqlx::query!(
+            "DELETE FROM login_tokens WHERE id = $1 RETURNING id",
+            user.token_id
+        )
+        .fetch_optional(&state.db)
+        .await
+        .unwrap()
+        .and(Some(user)),
+        result => result,
+    };
+
     match result {
         Some(user) => {
             if !user.verified {
                 sqlx::query!(
-                    "UPDATE users SET verified = $1 WHERE token = $2",
+                    "UPDATE users SET verified = $1 WHERE uid = $2",
                     true,
-                    params.token
+                    user.uid
                 )
                 .execute(&state.db)
                 .await
                 .unwrap();
             }
 
+            let user_agent = get_user_agent(&headers);
+            let ip = get_client_ip(&headers, remote_addr);
+
+            // Alert users when their login link is used from a device they have not logged in with before
+            let is_new_device = is_new_device(&state, user.uid, &user_agent).await;
+
+            if is_new_device {
+                let (name, email) = (user.name.clone(), user.email.clone());
+                let (user_agent, ip_prefix) = (user_agent.clone(), get_ip_prefix(&ip));
+                tokio::spawn(async move {
+                    let _ = send_new_device_alert(&name, &email, &user_agent, &ip_prefix).await;
+                });
+            }
+
+            // The login link is exchanged for a session, so the token itself never sits in a cookie
+            let session = create_session(&state, user.uid, &user_agent, &ip).await;
+            let role = get_role(&state.db, user.role).await;
+            let budget = get_budget(&state, user.uid, &role).await;
+
             (
-                [(
-                    header::SET_COOKIE,
-                    format!("token={}; Path=/; Secure; SameSite=Strict", params.token),
-                )],
+                AppendHeaders([
+                    (header::SET_COOKIE, session_cookie(&session)),
+                    (header::SET_COOKIE, CLEAR_LEGACY_TOKEN_COOKIE.to_owned()),
+                ]),
                 Json(LoginResponse {
                     email: Some(user.email),
                     name: Some(user.name),
                     verified: Some(user.verified),
                     role: Some(user.role),
+                    allowed_priorities: Some(
+                        role.allowed_priorities_for(user.allowed_priorities.as_deref()),
+                    ),
+                    badge: Some(role.badge_colour),
+                    budget: Some(budget),
                 }),
             )
                 .into_response()
@@ -80,7 +230,31 @@ pub async fn handle_login(
             name: None,
             verified: None,
             role: None,
+            allowed_priorities: None,
+            badge: None,
+            budget: None,
         })
         .into_response(),
     }
 }
+
+pub async fn handle_logout(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<LogoutRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    delete_session(&state, &headers).await;
+
+    (
+        StatusCode::OK,
+        [(header::SET_COOKIE, CLEAR_SESSION_COOKIE)],
+        "Logged out.",
+    )
+        .into_response()
+}
@@ -16,12 +16,16 @@
 use axum::{
     Json,
     extract::{Query, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::IntoResponse,
 };
+use axum_csrf::CsrfToken;
 use serde::{Deserialize, Serialize};
 
 use crate::constants::MID_HASH_KEY;
+use crate::roles::{PRIORITIES, get_role};
+use crate::routes::form::{MessagePriority, guest_priority_error, resolve_priority};
+use crate::session::get_session_user;
 use crate::state::AppState;
 use crate::utils::check_hash;
 
@@ -65,3 +69,222 @@ pub async fn handle_message_query(
         None => (StatusCode::BAD_REQUEST, "Requested message does not exist.").into_response(),
     }
 }
+
+#[derive(Deserialize)]
+pub struct MessageCancelRequest {
+    csrf_token: String,
+    mid: i32,
+    mid_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MessageEditRequest {
+    csrf_token: String,
+    mid: i32,
+    mid_hash: Option<String>,
+    message: String,
+    priority: MessagePriority,
+}
+
+// Priority of a message after the sender changes its priority from `submitted` to `requested`.
+//    An escalated message keeps its current priority unless the requested one is higher
+fn edited_priority<'a>(current: &'a str, submitted: &str, requested: &'a str) -> &'a str {
+    let rank = |priority: &str| PRIORITIES.iter().position(|p| *p == priority);
+    if rank(current) > rank(submitted) && rank(current) > rank(requested) {
+        current
+    } else {
+        requested
+    }
+}
+
+// A message can be changed by whoever holds its mid_hash, or by the logged-in user who sent it
+async fn is_message_sender(
+    state: &AppState,
+    mid: i32,
+    mid_hash: Option<&str>,
+    headers: &HeaderMap,
+) -> bool {
+    if let Some(mid_hash) = mid_hash
+        && check_hash(&mid.to_string(), mid_hash, MID_HASH_KEY.as_str())
+    {
+        return true;
+    }
+
+    match get_session_user(state, headers).await {
+        Some(user) => sqlx::query!(
+            "SELECT id FROM messages WHERE id = $1 AND user_uid = $2",
+            mid,
+            user.uid
+        )
+        .fetch_optional(&state.db)
+        .await
+        .unwrap()
+        .is_some(),
+        None => false,
+    }
+}
+
+pub async fn handle_message_cancel(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<MessageCancelRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    if !is_message_sender(&state, payload.mid, payload.mid_hash.as_deref(), &headers).await {
+        return (StatusCode::FORBIDDEN, "You cannot modify this message.").into_response();
+    }
+
+    // Only messages not yet picked up by the email worker can be cancelled
+    match sqlx::query!(
+        "UPDATE messages SET status = 'cancelled' WHERE id = $1 AND status IN ('scheduled', 'pending') RETURNING status",
+        payload.mid
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap()
+    {
+        Some(rec) => (
+            StatusCode::OK,
+            Json(MessageStatusResponse {
+                mid: payload.mid,
+                status: rec.status,
+            }),
+        )
+            .into_response(),
+        None => (
+            StatusCode::CONFLICT,
+            "Message has already been dispatched and can no longer be cancelled.",
+        )
+            .into_response(),
+    }
+}
+
+pub async fn handle_message_edit(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<MessageEditRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    if !is_message_sender(&state, payload.mid, payload.mid_hash.as_deref(), &headers).await {
+        return (StatusCode::FORBIDDEN, "You cannot modify this message.").into_response();
+    }
+
+    if payload.message.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "Message is required.").into_response();
+    }
+
+    // Messages follow the same priority rules as new ones, those without a sender account the guest rules.
+    //    Like submissions, the sender is locked until the edit is saved so budgets cannot be overspent
+    let mut tx = state.db.begin().await.unwrap();
+    let sender = sqlx::query!(
+        "SELECT u.uid, u.role, u.allowed_priorities FROM messages m JOIN users u ON u.uid = m.user_uid WHERE m.id = $1 FOR UPDATE OF u",
+        payload.mid
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .unwrap();
+
+    // Only messages not yet picked up by the email worker can be edited
+    let Some(current) = sqlx::query!(
+        "SELECT priority, submitted_priority FROM messages WHERE id = $1 AND status IN ('scheduled', 'pending') FOR UPDATE",
+        payload.mid
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .unwrap()
+    else {
+        return (
+            StatusCode::CONFLICT,
+            "Message has already been dispatched and can no longer be edited.",
+        )
+            .into_response();
+    };
+
+    // An unchanged priority keeps the message as it is, including any escalation
+    let (mut priority, mut submitted_priority) = (
+        current.priority.as_str(),
+        current.submitted_priority.as_str(),
+    );
+    if payload.priority.as_str() != current.submitted_priority {
+        let requested = payload.priority.as_str();
+        submitted_priority = if let Some(sender) = sender {
+            let role = get_role(&state.db, sender.role).await;
+            match resolve_priority(
+                &mut tx,
+                sender.uid,
+                &role,
+                sender.allowed_priorities.as_deref(),
+                requested,
+                Some(payload.mid),
+            )
+            .await
+            {
+                Ok(priority) => priority,
+                Err(response) => return response,
+            }
+        } else if let Some(response) = guest_priority_error(requested) {
+            return response;
+        } else {
+            requested
+        };
+        priority = edited_priority(priority, &current.submitted_priority, submitted_priority);
+    }
+
+    let status = sqlx::query_scalar!(
+        "UPDATE messages SET message = $1, priority = $2, submitted_priority = $3 WHERE id = $4 RETURNING status",
+        payload.message.trim(),
+        priority,
+        submitted_priority,
+        payload.mid
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+
+    (
+        StatusCode::OK,
+        Json(MessageStatusResponse {
+            mid: payload.mid,
+            status,
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edited_priorities() {
+        // Messages that were not escalated take the requested priority, lower or higher
+        assert_eq!(edited_priority("urgent", "urgent", "standard"), "standard");
+        assert_eq!(
+            edited_priority("standard", "standard", "immediate"),
+            "immediate"
+        );
+
+        // Escalated messages keep their priority unless the requested one is higher
+        assert_eq!(edited_priority("urgent", "standard", "standard"), "urgent");
+        assert_eq!(
+            edited_priority("immediate", "standard", "urgent"),
+            "immediate"
+        );
+        assert_eq!(edited_priority("urgent", "standard", "urgent"), "urgent");
+        assert_eq!(
+            edited_priority("urgent", "standard", "immediate"),
+            "immediate"
+        );
+    }
+}
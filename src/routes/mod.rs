@@ -13,12 +13,22 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+pub mod account;
+pub mod admin;
 pub mod apply;
+pub mod approval;
 pub mod assets;
+pub mod block;
 pub mod calendar;
+pub mod captcha;
 pub mod form;
+pub mod guest;
+pub mod invite;
 pub mod login;
 pub mod message;
 pub mod pages;
+pub mod passkey;
 pub mod resend_link;
+pub mod revoke;
+pub mod sessions;
 pub mod version;
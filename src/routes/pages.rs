@@ -14,10 +14,16 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use askama::Template;
-use axum::response::{Html, IntoResponse};
+use axum::{
+    extract::{Query, State},
+    response::{Html, IntoResponse},
+};
 use axum_csrf::CsrfToken;
+use serde::Deserialize;
 
-use crate::constants::RECAPTCHA_SITE_KEY;
+use crate::captcha::CaptchaWidget;
+use crate::routes::approval::ApplicationDecision;
+use crate::state::AppState;
 
 #[derive(Template)]
 #[template(path = "index.html")]
@@ -36,17 +42,20 @@ pub async fn serve_index(token: CsrfToken) -> impl IntoResponse {
 
 #[derive(Template)]
 #[template(path = "apply.html")]
-struct ApplyPageTemplate<'a> {
+struct ApplyPageTemplate {
     csrf_token: String,
-    recaptcha_site_token: &'a str,
+    captcha: Option<CaptchaWidget>,
 }
 
-pub async fn serve_apply_form(token: CsrfToken) -> impl IntoResponse {
+pub async fn serve_apply_form(
+    State(state): State<AppState>,
+    token: CsrfToken,
+) -> impl IntoResponse {
     let csrf_token = token.authenticity_token().unwrap();
 
     let template = ApplyPageTemplate {
         csrf_token,
-        recaptcha_site_token: RECAPTCHA_SITE_KEY.as_str(),
+        captcha: state.captcha.widget(),
     };
     let rendered = template.render().unwrap();
 
@@ -55,17 +64,138 @@ pub async fn serve_apply_form(token: CsrfToken) -> impl IntoResponse {
 
 #[derive(Template)]
 #[template(path = "link.html")]
-struct ResendLinkPageTemplate<'a> {
+struct ResendLinkPageTemplate {
     csrf_token: String,
-    recaptcha_site_token: &'a str,
+    captcha: Option<CaptchaWidget>,
 }
 
-pub async fn serve_resend_link_form(token: CsrfToken) -> impl IntoResponse {
+pub async fn serve_resend_link_form(
+    State(state): State<AppState>,
+    token: CsrfToken,
+) -> impl IntoResponse {
     let csrf_token = token.authenticity_token().unwrap();
 
     let template = ResendLinkPageTemplate {
         csrf_token,
-        recaptcha_site_token: RECAPTCHA_SITE_KEY.as_str(),
+        captcha: state.captcha.widget(),
+    };
+    let rendered = template.render().unwrap();
+
+    (token, Html(rendered)).into_response()
+}
+
+#[derive(Template)]
+#[template(path = "sessions.html")]
+struct SessionsPageTemplate {
+    csrf_token: String,
+}
+
+pub async fn serve_sessions_page(token: CsrfToken) -> impl IntoResponse {
+    let csrf_token = token.authenticity_token().unwrap();
+
+    let template = SessionsPageTemplate { csrf_token };
+    let rendered = template.render().unwrap();
+
+    (token, Html(rendered)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ApplicationPageQuery {
+    uid: i32,
+    decision: ApplicationDecision,
+    hash: String,
+}
+
+#[derive(Template)]
+#[template(path = "application.html")]
+struct ApplicationPageTemplate {
+    csrf_token: String,
+    uid: i32,
+    decision: String,
+    hash: String,
+}
+
+// Links in the owner's email only open this page, the decision is made with a POST request
+//    so that link previews and scanners cannot approve or reject applications
+pub async fn serve_application_page(
+    token: CsrfToken,
+    Query(query): Query<ApplicationPageQuery>,
+) -> impl IntoResponse {
+    let csrf_token = token.authenticity_token().unwrap();
+
+    let template = ApplicationPageTemplate {
+        csrf_token,
+        uid: query.uid,
+        decision: query.decision.to_string(),
+        hash: query.hash,
+    };
+    let rendered = template.render().unwrap();
+
+    (token, Html(rendered)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct BlockSenderPageQuery {
+    mid: i32,
+    hash: String,
+}
+
+#[derive(Template)]
+#[template(path = "block.html")]
+struct BlockSenderPageTemplate {
+    csrf_token: String,
+    mid: i32,
+    hash: String,
+}
+
+// Like application links, block links only open a page and the sender is blocked with a POST request
+pub async fn serve_block_sender_page(
+    token: CsrfToken,
+    Query(query): Query<BlockSenderPageQuery>,
+) -> impl IntoResponse {
+    let csrf_token = token.authenticity_token().unwrap();
+
+    let template = BlockSenderPageTemplate {
+        csrf_token,
+        mid: query.mid,
+        hash: query.hash,
+    };
+    let rendered = template.render().unwrap();
+
+    (token, Html(rendered)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct EmailChangePageQuery {
+    uid: i32,
+    email: String,
+    expires: i64,
+    hash: String,
+}
+
+#[derive(Template)]
+#[template(path = "email_change.html")]
+struct EmailChangePageTemplate {
+    csrf_token: String,
+    uid: i32,
+    email: String,
+    expires: i64,
+    hash: String,
+}
+
+// Links sent to new addresses only open this page, the change is confirmed with a POST request
+pub async fn serve_email_change_page(
+    token: CsrfToken,
+    Query(query): Query<EmailChangePageQuery>,
+) -> impl IntoResponse {
+    let csrf_token = token.authenticity_token().unwrap();
+
+    let template = EmailChangePageTemplate {
+        csrf_token,
+        uid: query.uid,
+        email: query.email,
+        expires: query.expires,
+        hash: query.hash,
     };
     let rendered = template.render().unwrap();
 
@@ -0,0 +1,397 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
+use axum_csrf::CsrfToken;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, time::Duration, time::Instant};
+use webauthn_rs::{
+    DEFAULT_AUTHENTICATOR_TIMEOUT,
+    fake::{FakePasskeyDistribution, WebauthnFakeCredentialGenerator},
+    prelude::{
+        Passkey, PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Url,
+        Uuid,
+    },
+};
+use webauthn_rs_proto::{
+    AllowCredentials, PublicKeyCredentialRequestOptions, UserVerificationPolicy,
+};
+
+use crate::constants::{HOMEPAGE_URL, MID_HASH_KEY};
+use crate::routes::login::send_new_device_alert;
+use crate::session::{create_session, get_session_user, is_new_device, session_cookie};
+use crate::state::AppState;
+use crate::utils::{
+    generate_hash, generate_random_token, get_client_ip, get_ip_prefix, get_user_agent,
+};
+
+// Passkey ceremonies not finished within this time have to be restarted
+const CEREMONY_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Anyone can start a passkey login, so beyond this many unfinished ones the oldest is dropped
+const MAX_LOGIN_CEREMONIES: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct PasskeyRegisterStartRequest {
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRegisterFinishRequest {
+    csrf_token: String,
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginStartRequest {
+    csrf_token: String,
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginFinishRequest {
+    csrf_token: String,
+    challenge_id: String,
+    credential: PublicKeyCredential,
+}
+
+#[derive(Serialize)]
+struct PasskeyLoginStartResponse {
+    challenge_id: String,
+    options: RequestChallengeResponse,
+}
+
+pub async fn handle_passkey_register_start(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<PasskeyRegisterStartRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let Some(user) = get_session_user(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Not logged in.").into_response();
+    };
+    if !user.verified {
+        return (
+            StatusCode::FORBIDDEN,
+            "Only verified users can register passkeys.",
+        )
+            .into_response();
+    }
+
+    // Passkeys already registered by this user are excluded, so the same device is not added twice
+    let existing = sqlx::query!("SELECT passkey FROM passkeys WHERE user_uid = $1", user.uid)
+        .fetch_all(&state.db)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|rec| serde_json::from_str::<Passkey>(&rec.passkey).ok())
+        .map(|passkey| passkey.cred_id().clone())
+        .collect::<Vec<_>>();
+
+    let (options, registration) = match state.webauthn.start_passkey_registration(
+        Uuid::from_u128(user.uid as u128),
+        &user.email,
+        &user.name,
+        Some(existing),
+    ) {
+        Ok(result) => result,
+        Err(ref err) => {
+            eprintln!("Passkey registration failed to start: {err:?}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Passkey registration failed to start.",
+            )
+                .into_response();
+        }
+    };
+
+    {
+        let mut ceremonies = state.ceremonies.lock().await;
+        ceremonies
+            .registrations
+            .retain(|_, (started, _)| started.elapsed() < CEREMONY_TIMEOUT);
+        ceremonies
+            .registrations
+            .insert(user.uid, (Instant::now(), registration));
+    }
+
+    Json(options).into_response()
+}
+
+pub async fn handle_passkey_register_finish(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<PasskeyRegisterFinishRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let Some(user) = get_session_user(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Not logged in.").into_response();
+    };
+
+    let registration = state
+        .ceremonies
+        .lock()
+        .await
+        .registrations
+        .remove(&user.uid);
+    let Some((_, registration)) =
+        registration.filter(|(started, _)| started.elapsed() < CEREMONY_TIMEOUT)
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Passkey registration expired, please try again.",
+        )
+            .into_response();
+    };
+
+    let passkey = match state
+        .webauthn
+        .finish_passkey_registration(&payload.credential, &registration)
+    {
+        Ok(passkey) => passkey,
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, "Passkey verification failed.").into_response();
+        }
+    };
+
+    match sqlx::query!(
+        "INSERT INTO passkeys (user_uid, credential_id, passkey, name) VALUES ($1, $2, $3, $4)",
+        user.uid,
+        hex::encode(passkey.cred_id()),
+        serde_json::to_string(&passkey).expect("Passkeys must be serialisable"),
+        payload.name.trim()
+    )
+    .execute(&state.db)
+    .await
+    {
+        Ok(_) => (StatusCode::CREATED, "Passkey registered.").into_response(),
+        Err(_) => (StatusCode::BAD_REQUEST, "Passkey already registered.").into_response(),
+    }
+}
+
+pub async fn handle_passkey_login_start(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    Json(payload): Json<PasskeyLoginStartRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    // Only verified users can log in with passkeys, login links remain the recovery option
+    let passkeys = sqlx::query!(
+        "SELECT p.user_uid, p.passkey FROM passkeys p JOIN users u ON p.user_uid = u.uid WHERE lower(u.email) = lower($1) AND u.verified AND u.status = 'active'",
+        payload.email.trim()
+    )
+    .fetch_all(&state.db)
+    .await
+    .unwrap();
+
+    // Emails without passkeys get a challenge too, so the response does not reveal who has any
+    let Some(uid) = passkeys.first().map(|rec| rec.user_uid) else {
+        return Json(PasskeyLoginStartResponse {
+            challenge_id: generate_random_token(),
+            options: decoy_login_options(&payload.email),
+        })
+        .into_response();
+    };
+    let passkeys = passkeys
+        .into_iter()
+        .filter_map(|rec| serde_json::from_str::<Passkey>(&rec.passkey).ok())
+        .collect::<Vec<_>>();
+
+    let (options, authentication) = match state.webauthn.start_passkey_authentication(&passkeys) {
+        Ok(result) => result,
+        Err(ref err) => {
+            eprintln!("Passkey login failed to start: {err:?}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Passkey login failed to start.",
+            )
+                .into_response();
+        }
+    };
+
+    let challenge_id = generate_random_token();
+    {
+        let mut ceremonies = state.ceremonies.lock().await;
+        ceremonies
+            .authentications
+            .retain(|_, (started, _, _)| started.elapsed() < CEREMONY_TIMEOUT);
+        if ceremonies.authentications.len() >= MAX_LOGIN_CEREMONIES
+            && let Some(oldest) = ceremonies
+                .authentications
+                .iter()
+                .min_by_key(|(_, (started, _, _))| *started)
+                .map(|(id, _)| id.clone())
+        {
+            ceremonies.authentications.remove(&oldest);
+        }
+        ceremonies
+            .authentications
+            .insert(challenge_id.clone(), (Instant::now(), uid, authentication));
+    }
+
+    Json(PasskeyLoginStartResponse {
+        challenge_id,
+        options,
+    })
+    .into_response()
+}
+
+pub async fn handle_passkey_login_finish(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<PasskeyLoginFinishRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let authentication = state
+        .ceremonies
+        .lock()
+        .await
+        .authentications
+        .remove(&payload.challenge_id);
+    let Some((_, uid, authentication)) =
+        authentication.filter(|(started, _, _)| started.elapsed() < CEREMONY_TIMEOUT)
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Passkey login expired, please try again.",
+        )
+            .into_response();
+    };
+
+    let result = match state
+        .webauthn
+        .finish_passkey_authentication(&payload.credential, &authentication)
+    {
+        Ok(result) => result,
+        Err(_) => {
+            return (StatusCode::UNAUTHORIZED, "Passkey verification failed.").into_response();
+        }
+    };
+
+    // Keep the stored signature counter up to date
+    let credential_id = hex::encode(result.cred_id());
+    if let Some(rec) = sqlx::query!(
+        "SELECT passkey FROM passkeys WHERE credential_id = $1 AND user_uid = $2",
+        credential_id,
+        uid
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap()
+        && let Ok(mut passkey) = serde_json::from_str::<Passkey>(&rec.passkey)
+    {
+        passkey.update_credential(&result);
+        sqlx::query!(
+            "UPDATE passkeys SET passkey = $1, last_used_time = now() WHERE credential_id = $2",
+            serde_json::to_string(&passkey).expect("Passkeys must be serialisable"),
+            credential_id
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+    }
+
+    let user_agent = get_user_agent(&headers);
+    let ip = get_client_ip(&headers, remote_addr);
+
+    // Alert users when a passkey is used from a device they have not logged in with before
+    if is_new_device(&state, uid, &user_agent).await {
+        let user = sqlx::query!("SELECT name, email FROM users WHERE uid = $1", uid)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        let (user_agent, ip_prefix) = (user_agent.clone(), get_ip_prefix(&ip));
+        tokio::spawn(async move {
+            let _ = send_new_device_alert(&user.name, &user.email, &user_agent, &ip_prefix).await;
+        });
+    }
+
+    let session = create_session(&state, uid, &user_agent, &ip).await;
+
+    (
+        StatusCode::OK,
+        [(header::SET_COOKIE, session_cookie(&session))],
+        "Logged in.",
+    )
+        .into_response()
+}
+
+// Passkeys are bound to the homepage, its host is the relying party id
+pub fn relying_party_id(origin: &Url) -> String {
+    origin
+        .host_str()
+        .expect("HOMEPAGE_URL must have a host")
+        .to_owned()
+}
+
+// Challenge for made up credentials, which stay the same for each email like real ones would
+fn decoy_login_options(email: &str) -> RequestChallengeResponse {
+    let key = generate_hash("passkey decoys", &MID_HASH_KEY);
+    let email = email.trim().to_lowercase();
+    // Real logins always list a passkey, so an empty list from the generator is replaced
+    let credential_ids =
+        WebauthnFakeCredentialGenerator::<FakePasskeyDistribution>::new(key.as_bytes())
+            .and_then(|generator| generator.generate(email.as_bytes()))
+            .ok()
+            .filter(|ids| !ids.is_empty())
+            .unwrap_or_else(|| vec![hex::decode(generate_hash(&email, &key)).unwrap().into()]);
+    let origin = Url::parse(&HOMEPAGE_URL).expect("HOMEPAGE_URL must be a valid URL");
+
+    RequestChallengeResponse {
+        public_key: PublicKeyCredentialRequestOptions {
+            challenge: rand::random::<[u8; 32]>().to_vec().into(),
+            timeout: Some(DEFAULT_AUTHENTICATOR_TIMEOUT.as_millis() as u32),
+            rp_id: relying_party_id(&origin),
+            allow_credentials: credential_ids
+                .into_iter()
+                .map(|id| AllowCredentials {
+                    type_: "public-key".to_owned(),
+                    id: id.as_ref().into(),
+                    transports: None,
+                })
+                .collect(),
+            user_verification: UserVerificationPolicy::Required,
+            hints: None,
+            extensions: None,
+        },
+        mediation: None,
+    }
+}
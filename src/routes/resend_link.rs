@@ -13,31 +13,39 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
 use axum_csrf::CsrfToken;
-use reqwest::Client;
 use serde::Deserialize;
+use std::net::SocketAddr;
 
-use crate::constants::{ALLOW_MODIFY_DB, RECAPTCHA_SECRET_KEY};
-use crate::routes::apply::send_login_link;
+use crate::blocklist::is_sender_blocked;
+use crate::captcha::verify_captcha;
+use crate::constants::ALLOW_MODIFY_DB;
+use crate::routes::apply::{issue_login_token, login_link_description, send_login_link};
 use crate::state::AppState;
+use crate::utils::{get_client_ip, normalise_email};
 
 #[derive(Deserialize)]
 pub struct ResendLinkRequest {
     csrf_token: String,
     email: String,
-    name: String,
-    recaptcha: String,
-}
-
-#[derive(Deserialize)]
-struct RecaptchaResponse {
-    success: bool,
+    #[serde(alias = "recaptcha")]
+    captcha: String,
+    // Invalidate all previously issued login links before sending a new one
+    #[serde(default)]
+    rotate: bool,
 }
 
 pub async fn handle_resend_link(
     State(state): State<AppState>,
     token: CsrfToken,
+    headers: HeaderMap,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
     Json(payload): Json<ResendLinkRequest>,
 ) -> impl IntoResponse {
     // If not prod or beta, do not modify database. See constants
@@ -54,73 +62,62 @@ pub async fn handle_resend_link(
         return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
     }
 
-    if payload.recaptcha.is_empty() {
-        return (StatusCode::BAD_REQUEST, "reCAPTCHA verification failed").into_response();
-    }
+    let Some(email) = normalise_email(&payload.email) else {
+        return (StatusCode::BAD_REQUEST, "Email invalid.").into_response();
+    };
 
-    let client = Client::new();
-    let params = [
-        ("secret", RECAPTCHA_SECRET_KEY.as_str()),
-        ("response", &payload.recaptcha),
-    ];
+    let ip = get_client_ip(&headers, remote_addr);
+    if let Err(response) = verify_captcha(state.captcha.as_ref(), &payload.captcha, &ip).await {
+        return response;
+    }
 
-    let res = client
-        .post("https://www.google.com/recaptcha/api/siteverify")
-        .form(&params)
-        .send()
-        .await;
+    if let Some(rec) = sqlx::query!(
+        "SELECT uid, name, email FROM users WHERE lower(email) = $1 AND status = 'active'",
+        email
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap()
+        // Blocked senders get the same response, without a link being sent
+        && !is_sender_blocked(&state.db, Some(rec.uid), &email, &ip).await
+    {
+        sqlx::query!("UPDATE users SET verified = false WHERE uid = $1", rec.uid)
+            .execute(&state.db)
+            .await
+            .unwrap();
 
-    match res {
-        Ok(response) => {
-            if let Ok(recaptcha_response) = response.json::<RecaptchaResponse>().await
-                && recaptcha_response.success
-            {
-                if let Some(rec) = sqlx::query!(
-                    "SELECT token FROM users WHERE (email, name) = ($1, $2)",
-                    payload.email.trim(),
-                    payload.name.trim()
-                )
-                .fetch_optional(&state.db)
+        // Only login links are rotated, since anyone with the email can get here.
+        //    Sessions can only be revoked while signed in
+        if payload.rotate {
+            sqlx::query!("DELETE FROM login_tokens WHERE user_uid = $1", rec.uid)
+                .execute(&state.db)
                 .await
-                .unwrap()
-                {
-                    sqlx::query!(
-                        "UPDATE users SET verified = false WHERE (email, name) = ($1, $2)",
-                        payload.email.trim(),
-                        payload.name.trim()
-                    )
-                    .execute(&state.db)
-                    .await
-                    .unwrap();
+                .unwrap();
+        }
 
-                    tokio::spawn(async move {
-                        let _ =
-                            send_login_link(payload.name.trim(), payload.email.trim(), &rec.token)
-                                .await;
-                    });
+        // Stored tokens are hashed and cannot be read back, so a new one is issued
+        let token = issue_login_token(&state, rec.uid).await;
 
-                    /* if let Err(ref err) = link_result {
-                        eprintln!("Login link resender failed to resend login link: {:?}", err);
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            "Login link email failed to send.",
-                        )
-                            .into_response();
-                    } */
-                }
+        tokio::spawn(async move {
+            let _ = send_login_link(&rec.name, &rec.email, &token).await;
+        });
 
-                return (
-                    StatusCode::ACCEPTED,
-                    "If your details are correct, please check your email for your permanent login link.",
-                )
-                    .into_response();
-            }
-            (StatusCode::BAD_REQUEST, "reCAPTCHA verification failed").into_response()
-        }
-        Err(_) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Error verifying reCAPTCHA",
-        )
-            .into_response(),
+        /* if let Err(ref err) = link_result {
+            eprintln!("Login link resender failed to resend login link: {:?}", err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Login link email failed to send.",
+            )
+                .into_response();
+        } */
     }
+
+    (
+        StatusCode::ACCEPTED,
+        format!(
+            "If an account uses this email, please check it for your {}.",
+            login_link_description()
+        ),
+    )
+        .into_response()
 }
@@ -0,0 +1,72 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
+use axum_csrf::CsrfToken;
+use serde::Deserialize;
+
+use crate::routes::apply::{issue_login_token, send_login_link};
+use crate::session::{CLEAR_SESSION_COOKIE, get_session_user};
+use crate::state::AppState;
+
+#[derive(Deserialize)]
+pub struct RevokeRequest {
+    csrf_token: String,
+}
+
+pub async fn handle_revoke_all(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<RevokeRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let Some(user) = get_session_user(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Not logged in.").into_response();
+    };
+
+    sqlx::query!("DELETE FROM login_tokens WHERE user_uid = $1", user.uid)
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+    sqlx::query!("DELETE FROM sessions WHERE user_uid = $1", user.uid)
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+    // Without a fresh link the user would have to go through resend-link to log in again
+    let new_token = issue_login_token(&state, user.uid).await;
+
+    tokio::spawn(async move {
+        let _ = send_login_link(&user.name, &user.email, &new_token).await;
+    });
+
+    (
+        StatusCode::OK,
+        [(header::SET_COOKIE, CLEAR_SESSION_COOKIE)],
+        "All login links and sessions have been revoked. Please check your email for your new login link.",
+    )
+        .into_response()
+}
@@ -0,0 +1,110 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use axum_csrf::CsrfToken;
+use serde::{Deserialize, Serialize};
+
+use crate::constants::CALENDAR_DATETIME_FORMAT;
+use crate::session::{get_session_hash, get_session_user};
+use crate::state::AppState;
+use crate::utils::get_ip_prefix;
+
+#[derive(Deserialize)]
+pub struct SessionRevokeRequest {
+    csrf_token: String,
+    id: i32,
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    id: i32,
+    created_time: String,
+    last_seen_time: String,
+    user_agent: String,
+    ip_prefix: String,
+    current: bool,
+}
+
+pub async fn handle_sessions_list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(user) = get_session_user(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Not logged in.").into_response();
+    };
+    let current_hash = get_session_hash(&headers);
+
+    let sessions = sqlx::query!(
+        "SELECT id, session_hash, created_time, last_seen_time, user_agent, ip FROM sessions WHERE user_uid = $1 AND expires_time > now() ORDER BY last_seen_time DESC",
+        user.uid
+    )
+    .fetch_all(&state.db)
+    .await
+    .unwrap();
+
+    Json(
+        sessions
+            .into_iter()
+            .map(|s| SessionResponse {
+                id: s.id,
+                created_time: s.created_time.format(CALENDAR_DATETIME_FORMAT).to_string(),
+                last_seen_time: s
+                    .last_seen_time
+                    .format(CALENDAR_DATETIME_FORMAT)
+                    .to_string(),
+                user_agent: s.user_agent,
+                ip_prefix: get_ip_prefix(&s.ip),
+                current: current_hash.as_ref() == Some(&s.session_hash),
+            })
+            .collect::<Vec<_>>(),
+    )
+    .into_response()
+}
+
+pub async fn handle_session_revoke(
+    State(state): State<AppState>,
+    token: CsrfToken,
+    headers: HeaderMap,
+    Json(payload): Json<SessionRevokeRequest>,
+) -> impl IntoResponse {
+    // Validate csrf token
+    if token.verify(&payload.csrf_token).is_err() {
+        return (StatusCode::BAD_REQUEST, "CSRF token invalid.").into_response();
+    }
+
+    let Some(user) = get_session_user(&state, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "Not logged in.").into_response();
+    };
+
+    // Users can only revoke their own sessions
+    match sqlx::query!(
+        "DELETE FROM sessions WHERE id = $1 AND user_uid = $2 RETURNING id",
+        payload.id,
+        user.uid
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap()
+    {
+        Some(_) => (StatusCode::OK, "Session revoked.").into_response(),
+        None => (StatusCode::BAD_REQUEST, "Requested session does not exist.").into_response(),
+    }
+}
@@ -0,0 +1,150 @@
+// Enviame - Full-stack Priority Messenger with a Rust backend that respects priority settings and delivers messages.
+// Copyright (C) 2025 Brian Chen (differental)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use axum::http::{HeaderMap, header};
+use chrono::Utc;
+
+use crate::constants::{SESSION_KEY, SESSION_LIFETIME};
+use crate::state::AppState;
+use crate::utils::{
+    check_hash, generate_hash, generate_random_token, get_device_family, hash_token,
+};
+
+pub struct SessionUser {
+    pub uid: i32,
+    pub email: String,
+    pub name: String,
+    pub verified: bool,
+    pub role: i32,
+    // Overrides the allowed priorities of the user's role if set
+    pub allowed_priorities: Option<Vec<String>>,
+}
+
+fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Session cookies are "<id>.<signature>", only the hash of the full value is stored
+fn get_session_cookie(headers: &HeaderMap) -> Option<&str> {
+    let cookie = get_cookie(headers, "session")?;
+    let (session_id, signature) = cookie.split_once('.')?;
+
+    check_hash(session_id, signature, &SESSION_KEY).then_some(cookie)
+}
+
+pub fn session_cookie(session: &str) -> String {
+    format!(
+        "session={session}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+        SESSION_LIFETIME.num_seconds()
+    )
+}
+
+pub const CLEAR_SESSION_COOKIE: &str =
+    "session=; Path=/; Max-Age=0; HttpOnly; Secure; SameSite=Strict";
+
+// Login tokens used to be stored in this cookie, before sessions existed
+pub const CLEAR_LEGACY_TOKEN_COOKIE: &str = "token=; Path=/; Max-Age=0; Secure; SameSite=Strict";
+
+// Creates a session for the user, returning the cookie value
+pub async fn create_session(state: &AppState, uid: i32, user_agent: &str, ip: &str) -> String {
+    let session_id = generate_random_token();
+    let session = format!("{session_id}.{}", generate_hash(&session_id, &SESSION_KEY));
+
+    sqlx::query!("DELETE FROM sessions WHERE expires_time <= now()")
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+    sqlx::query!(
+        "INSERT INTO sessions (user_uid, session_hash, expires_time, user_agent, ip) VALUES ($1, $2, $3, $4, $5)",
+        uid,
+        hash_token(&session),
+        Utc::now() + SESSION_LIFETIME,
+        user_agent,
+        ip
+    )
+    .execute(&state.db)
+    .await
+    .unwrap();
+
+    sqlx::query!(
+        "INSERT INTO login_devices (user_uid, device) VALUES ($1, $2) ON CONFLICT (user_uid, device) DO UPDATE SET last_login_time = now()",
+        uid,
+        get_device_family(user_agent)
+    )
+    .execute(&state.db)
+    .await
+    .unwrap();
+
+    session
+}
+
+// Whether the user has logged in before, but never with this browser and OS.
+//    Devices are remembered after their sessions end, sessions from before devices were recorded count too
+pub async fn is_new_device(state: &AppState, uid: i32, user_agent: &str) -> bool {
+    let device = get_device_family(user_agent);
+
+    let known_devices =
+        sqlx::query_scalar!("SELECT device FROM login_devices WHERE user_uid = $1", uid)
+            .fetch_all(&state.db)
+            .await
+            .unwrap();
+
+    let session_devices: Vec<String> =
+        sqlx::query_scalar!("SELECT user_agent FROM sessions WHERE user_uid = $1", uid)
+            .fetch_all(&state.db)
+            .await
+            .unwrap()
+            .iter()
+            .map(|user_agent| get_device_family(user_agent))
+            .collect();
+
+    let has_history = !known_devices.is_empty() || !session_devices.is_empty();
+    has_history && !known_devices.contains(&device) && !session_devices.contains(&device)
+}
+
+// Hash of the current session cookie, used to tell the current session apart from others
+pub fn get_session_hash(headers: &HeaderMap) -> Option<String> {
+    get_session_cookie(headers).map(hash_token)
+}
+
+pub async fn get_session_user(state: &AppState, headers: &HeaderMap) -> Option<SessionUser> {
+    let session_hash = get_session_hash(headers)?;
+
+    sqlx::query_as!(
+        SessionUser,
+        "WITH s AS (UPDATE sessions SET last_seen_time = now() WHERE session_hash = $1 AND expires_time > now() RETURNING user_uid) SELECT u.uid, u.email, u.name, u.verified, u.role, u.allowed_priorities FROM users u JOIN s ON s.user_uid = u.uid WHERE u.status = 'active'",
+        session_hash
+    )
+    .fetch_optional(&state.db)
+    .await
+    .unwrap()
+}
+
+pub async fn delete_session(state: &AppState, headers: &HeaderMap) {
+    if let Some(session_hash) = get_session_hash(headers) {
+        sqlx::query!("DELETE FROM sessions WHERE session_hash = $1", session_hash)
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+}
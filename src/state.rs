@@ -13,18 +13,343 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use chrono::{DateTime, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
 use sqlx::PgPool;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::{collections::HashMap, sync::Arc, time::Instant};
+use tokio::sync::{Mutex, RwLock};
+use webauthn_rs::prelude::{PasskeyAuthentication, PasskeyRegistration, Webauthn};
+
+use crate::captcha::CaptchaVerifier;
+use crate::constants::{ESCALATE_IMMEDIATE_BEFORE, ESCALATE_URGENT_BEFORE};
+use crate::ratelimit::RateLimiter;
+use crate::roles::PRIORITIES;
 
 #[derive(Clone, PartialEq)]
 pub struct CalendarCache {
     pub is_busy: bool,
     pub timestamp: String,
+    // Only set once the calendar has been fetched and is busy, used to hold standard messages
+    pub busy_until: Option<DateTime<Utc>>,
+}
+
+impl CalendarCache {
+    // Time until which the email worker holds messages of this priority, if they are held at all
+    pub fn held_until(&self, priority: &str) -> Option<DateTime<Utc>> {
+        self.held_until_at(priority, Utc::now())
+    }
+
+    // Only standard messages are held, while the calendar is busy or during sleep hours
+    fn held_until_at(&self, priority: &str, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match priority {
+            "standard" => self.busy_until.filter(|t| *t > at),
+            _ => None,
+        }
+    }
+
+    // Projected delivery time of a new message, following the email worker's rules:
+    //    scheduled messages wait for their delivery time, held messages wait for the
+    //    hold to end or for escalation ahead of their deadline to a priority the
+    //    sender can send, unless the sender's role bypasses sleep
+    pub fn projected_delivery(
+        &self,
+        priority: &str,
+        deliver_after: Option<DateTime<Utc>>,
+        needed_by: Option<DateTime<Utc>>,
+        escalates_to: &[&str],
+        bypass_sleep: bool,
+    ) -> DateTime<Utc> {
+        self.projected_delivery_at(
+            Utc::now(),
+            priority,
+            deliver_after,
+            needed_by,
+            escalates_to,
+            bypass_sleep,
+        )
+    }
+
+    fn projected_delivery_at(
+        &self,
+        now: DateTime<Utc>,
+        priority: &str,
+        deliver_after: Option<DateTime<Utc>>,
+        needed_by: Option<DateTime<Utc>>,
+        escalates_to: &[&str],
+        bypass_sleep: bool,
+    ) -> DateTime<Utc> {
+        let pending_from = deliver_after.unwrap_or(now).max(now);
+        let released = |priority: &str, at: DateTime<Utc>| {
+            if bypass_sleep {
+                return at;
+            }
+            self.held_until_at(priority, at).unwrap_or(at)
+        };
+        let rank = |priority: &str| PRIORITIES.iter().position(|p| *p == priority);
+
+        // The message goes out once it is released at its own priority, or at any priority it escalates to
+        [
+            ("urgent", ESCALATE_URGENT_BEFORE),
+            ("immediate", ESCALATE_IMMEDIATE_BEFORE),
+        ]
+        .into_iter()
+        .filter(|(escalated, _)| {
+            rank(escalated) > rank(priority) && escalates_to.contains(escalated)
+        })
+        .filter_map(|(escalated, before)| {
+            needed_by.map(|needed_by| released(escalated, (needed_by - before).max(pending_from)))
+        })
+        .fold(released(priority, pending_from), DateTime::min)
+    }
+}
+
+// Nightly sleep period, between two local times that may span midnight
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SleepHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub tz: Tz,
+}
+
+impl SleepHours {
+    // Parses "23:00-08:00"
+    pub fn parse(s: &str, tz: Tz) -> Option<Self> {
+        let (start, end) = s.split_once('-')?;
+        let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?;
+        let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?;
+        (start != end).then_some(Self { start, end, tz })
+    }
+
+    // Sleep periods starting yesterday, today and tomorrow in local time that have not ended yet
+    pub fn periods_around(&self, now: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let today = now.with_timezone(&self.tz).date_naive();
+        [-1, 0, 1]
+            .into_iter()
+            .filter_map(|offset| {
+                let start_date = today + TimeDelta::days(offset);
+                let end_date = if self.start < self.end {
+                    start_date
+                } else {
+                    start_date.succ_opt()?
+                };
+                Some((
+                    self.local_to_utc(start_date.and_time(self.start))?,
+                    self.local_to_utc(end_date.and_time(self.end))?,
+                ))
+            })
+            .filter(|(_, end)| *end >= now)
+            .collect()
+    }
+
+    // Skip past local times that fall in a DST gap
+    fn local_to_utc(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        self.tz
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                self.tz
+                    .from_local_datetime(&(local + TimeDelta::hours(1)))
+                    .earliest()
+            })
+            .map(|t| t.with_timezone(&Utc))
+    }
+}
+
+// In-progress passkey ceremonies, kept in memory since they only last a few minutes
+#[derive(Default)]
+pub struct PasskeyCeremonies {
+    // Keyed by user uid
+    pub registrations: HashMap<i32, (Instant, PasskeyRegistration)>,
+    // Keyed by challenge id, along with the uid of the user logging in
+    pub authentications: HashMap<String, (Instant, i32, PasskeyAuthentication)>,
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: PgPool,
     pub status: Arc<RwLock<CalendarCache>>,
+    pub webauthn: Arc<Webauthn>,
+    pub ceremonies: Arc<Mutex<PasskeyCeremonies>>,
+    pub rate_limiter: Arc<Mutex<RateLimiter>>,
+    pub captcha: Arc<dyn CaptchaVerifier>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn cache(busy_until: Option<&str>) -> CalendarCache {
+        CalendarCache {
+            is_busy: busy_until.is_some(),
+            timestamp: String::new(),
+            busy_until: busy_until.map(at),
+        }
+    }
+
+    #[test]
+    fn sleep_hours_parse() {
+        assert!(SleepHours::parse("23:00-08:00", Tz::UTC).is_some());
+        assert!(SleepHours::parse(" 01:30 - 06:00 ", Tz::UTC).is_some());
+        assert_eq!(SleepHours::parse("07:00-07:00", Tz::UTC), None);
+        assert_eq!(SleepHours::parse("23:00", Tz::UTC), None);
+        assert_eq!(SleepHours::parse("25:00-07:00", Tz::UTC), None);
+    }
+
+    #[test]
+    fn sleep_hours_periods() {
+        let night = SleepHours::parse("23:00-08:00", Tz::UTC).unwrap();
+        assert_eq!(
+            night.periods_around(at("2026-01-06T03:00:00Z")),
+            [
+                (at("2026-01-05T23:00:00Z"), at("2026-01-06T08:00:00Z")),
+                (at("2026-01-06T23:00:00Z"), at("2026-01-07T08:00:00Z")),
+                (at("2026-01-07T23:00:00Z"), at("2026-01-08T08:00:00Z")),
+            ]
+        );
+        assert_eq!(
+            night.periods_around(at("2026-01-06T12:00:00Z"))[0],
+            (at("2026-01-06T23:00:00Z"), at("2026-01-07T08:00:00Z"))
+        );
+
+        // Periods within a day start on the next day once today's has ended
+        let early = SleepHours::parse("01:00-06:00", Tz::UTC).unwrap();
+        assert_eq!(
+            early.periods_around(at("2026-01-06T23:30:00Z")),
+            [(at("2026-01-07T01:00:00Z"), at("2026-01-07T06:00:00Z"))]
+        );
+
+        // 23:00-08:00 in London is 22:00-07:00 UTC during summer time
+        let london = SleepHours::parse("23:00-08:00", Tz::Europe__London).unwrap();
+        assert_eq!(
+            london.periods_around(at("2026-07-01T22:30:00Z"))[0],
+            (at("2026-07-01T22:00:00Z"), at("2026-07-02T07:00:00Z"))
+        );
+
+        // Clocks go forward at 01:00 in London on 2026-03-29, so a start at 01:30 moves past the gap
+        let gap = SleepHours::parse("01:30-06:00", Tz::Europe__London).unwrap();
+        assert_eq!(
+            gap.periods_around(at("2026-03-28T12:00:00Z"))[0],
+            (at("2026-03-29T01:30:00Z"), at("2026-03-29T05:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn projected_delivery_busy() {
+        let now = at("2026-01-06T12:00:00Z");
+        let busy = cache(Some("2026-01-06T14:00:00Z"));
+        let project = |priority, bypass_sleep| {
+            busy.projected_delivery_at(now, priority, None, None, &PRIORITIES, bypass_sleep)
+        };
+
+        assert_eq!(project("standard", false), at("2026-01-06T14:00:00Z"));
+        assert_eq!(project("standard", true), now);
+        assert_eq!(project("urgent", false), now);
+        assert_eq!(project("immediate", false), now);
+
+        // A busy period that already ended holds nothing
+        let ended = cache(Some("2026-01-06T11:00:00Z"));
+        assert_eq!(
+            ended.projected_delivery_at(now, "standard", None, None, &PRIORITIES, false),
+            now
+        );
+
+        // Scheduled messages are only held if their delivery time falls in the busy period
+        assert_eq!(
+            busy.projected_delivery_at(
+                now,
+                "standard",
+                Some(at("2026-01-06T13:00:00Z")),
+                None,
+                &PRIORITIES,
+                false
+            ),
+            at("2026-01-06T14:00:00Z")
+        );
+        assert_eq!(
+            busy.projected_delivery_at(
+                now,
+                "standard",
+                Some(at("2026-01-06T15:00:00Z")),
+                None,
+                &PRIORITIES,
+                false
+            ),
+            at("2026-01-06T15:00:00Z")
+        );
+    }
+
+    #[test]
+    fn projected_delivery_needed_by() {
+        let now = at("2026-01-06T12:00:00Z");
+        let busy = cache(Some("2026-01-06T17:00:00Z"));
+
+        // Escalated to urgent an hour before the deadline, ahead of the busy period ending
+        assert_eq!(
+            busy.projected_delivery_at(
+                now,
+                "standard",
+                None,
+                Some(at("2026-01-06T15:00:00Z")),
+                &PRIORITIES,
+                false
+            ),
+            at("2026-01-06T14:00:00Z")
+        );
+
+        // Senders who cannot send urgent messages wait for the busy period to end
+        assert_eq!(
+            busy.projected_delivery_at(
+                now,
+                "standard",
+                None,
+                Some(at("2026-01-06T15:00:00Z")),
+                &["standard"],
+                false
+            ),
+            at("2026-01-06T17:00:00Z")
+        );
+
+        // Without urgent, the deadline only releases the message once it can escalate to immediate
+        assert_eq!(
+            busy.projected_delivery_at(
+                now,
+                "standard",
+                None,
+                Some(at("2026-01-06T15:00:00Z")),
+                &["standard", "immediate"],
+                false
+            ),
+            at("2026-01-06T14:50:00Z")
+        );
+
+        // A deadline after the busy period ends changes nothing
+        assert_eq!(
+            busy.projected_delivery_at(
+                now,
+                "standard",
+                None,
+                Some(at("2026-01-06T20:00:00Z")),
+                &PRIORITIES,
+                false
+            ),
+            at("2026-01-06T17:00:00Z")
+        );
+
+        // Deadlines that already passed deliver right away
+        assert_eq!(
+            busy.projected_delivery_at(
+                now,
+                "standard",
+                None,
+                Some(at("2026-01-06T12:05:00Z")),
+                &PRIORITIES,
+                false
+            ),
+            now
+        );
+    }
 }
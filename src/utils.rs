@@ -13,12 +13,14 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use axum::http::{HeaderMap, header};
 use hmac::{Hmac, KeyInit, Mac};
 use lettre::{Message, Transport, message::header::ContentType};
 use rand::{RngExt, distr::Alphanumeric};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
 
-use crate::constants::MAILER;
+use crate::constants::{MAILER, TRUSTED_PROXIES};
 
 pub fn generate_random_token() -> String {
     rand::rng()
@@ -28,6 +30,43 @@ pub fn generate_random_token() -> String {
         .collect()
 }
 
+// Short numeric code, for codes that have to be typed in by hand
+pub fn generate_numeric_code(len: usize) -> String {
+    let mut rng = rand::rng();
+    (0..len)
+        .map(|_| char::from(b'0' + rng.random_range(0..10)))
+        .collect()
+}
+
+// Email addresses identify users regardless of case, so they are stored trimmed and in lower case.
+//    None if the address is not plausibly valid
+pub fn normalise_email(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    let (local, domain) = email.split_once('@')?;
+
+    let is_valid = !local.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && email.len() <= 254
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control());
+
+    is_valid.then_some(email)
+}
+
+// Escapes LIKE wildcards, so that the string only matches itself in patterns using ESCAPE '\'
+pub fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// Login tokens are only stored as digests, so a leaked database does not leak logins
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
 pub fn capitalize_first(s: String) -> String {
     let mut chars = s.chars();
     match chars.next() {
@@ -45,9 +84,15 @@ pub fn generate_hash(str: &str, hash_key: &str) -> String {
     hex::encode(code_bytes) // Convert to hex string
 }
 
+// Compared in constant time, so response timing does not reveal how much of a guess is right
 pub fn check_hash(str: &str, provided_hash: &str, hash_key: &str) -> bool {
-    let expected_hash = generate_hash(str, hash_key);
-    expected_hash == provided_hash
+    let Ok(provided) = hex::decode(provided_hash) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(hash_key.as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(str.as_bytes());
+    mac.verify_slice(&provided).is_ok()
 }
 
 pub fn escape_html(str: String) -> String {
@@ -78,3 +123,359 @@ pub async fn send_email(
     MAILER.send(&email)?;
     Ok(())
 }
+
+pub fn get_user_agent(headers: &HeaderMap) -> String {
+    headers
+        .get("user-agent")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string()
+}
+
+// Browser and OS family of a user agent, e.g. "Firefox on Windows", which stays the same across
+//    browser updates. User agents of neither known browsers nor known systems keep their product name
+pub fn get_device_family(user_agent: &str) -> String {
+    let has = |token: &str| user_agent.contains(token);
+
+    let browser = if has("Edg/") || has("EdgA/") || has("EdgiOS/") {
+        Some("Edge")
+    } else if has("OPR/") || has("Opera") {
+        Some("Opera")
+    } else if has("SamsungBrowser/") {
+        Some("Samsung Internet")
+    } else if has("Firefox/") || has("FxiOS/") {
+        Some("Firefox")
+    } else if has("Chrome/") || has("CriOS/") || has("Chromium/") {
+        Some("Chrome")
+    } else if has("Safari/") {
+        Some("Safari")
+    } else {
+        None
+    };
+
+    let os = if has("Windows") {
+        Some("Windows")
+    } else if has("iPhone") || has("iPad") || has("iPod") {
+        Some("iOS")
+    } else if has("Android") {
+        Some("Android")
+    } else if has("CrOS") {
+        Some("ChromeOS")
+    } else if has("Macintosh") || has("Mac OS X") {
+        Some("macOS")
+    } else if has("Linux") {
+        Some("Linux")
+    } else {
+        None
+    };
+
+    match (browser, os) {
+        (None, None) => user_agent
+            .split(['/', ' '])
+            .next()
+            .unwrap_or_default()
+            .to_owned(),
+        (browser, os) => format!(
+            "{} on {}",
+            browser.unwrap_or("Unknown browser"),
+            os.unwrap_or("unknown OS")
+        ),
+    }
+}
+
+// The client address is only read from forwarding headers when the connection comes from a trusted proxy.
+//    Hops are read from the last one, the first that is not a trusted proxy is the client
+pub fn get_client_ip(headers: &HeaderMap, remote_addr: SocketAddr) -> String {
+    client_ip(headers, remote_addr, &TRUSTED_PROXIES)
+}
+
+fn client_ip(headers: &HeaderMap, remote_addr: SocketAddr, trusted_proxies: &[IpRange]) -> String {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|range| range.contains(ip));
+
+    let mut client = remote_addr.ip().to_canonical();
+    if is_trusted(client) {
+        for hop in forwarded_hops(headers).into_iter().rev() {
+            // Unknown or obfuscated hops end the chain at the proxy that reported them
+            let Some(ip) = hop else {
+                break;
+            };
+            client = ip;
+            if !is_trusted(ip) {
+                break;
+            }
+        }
+    }
+
+    client.to_string()
+}
+
+// Addresses in the Forwarded header, or in X-Forwarded-For without one, from the client to the last proxy
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded = headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>();
+
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|v| v.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, hop)| parse_hop(hop))
+            })
+            .collect();
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(parse_hop)
+        .collect()
+}
+
+// Hops may be quoted and carry a port, with IPv6 addresses in brackets
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    let ip = hop
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            hop.strip_prefix('[')
+                .and_then(|h| h.split_once(']'))
+                .and_then(|(ip, _)| ip.parse().ok())
+        })?;
+
+    Some(ip.to_canonical())
+}
+
+// An address range in CIDR notation, or a single address without a prefix length
+#[derive(Clone, Copy)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    pub fn parse(range: &str) -> Option<Self> {
+        let (addr, prefix) = match range.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (range.trim(), None),
+        };
+        let addr = addr.parse::<IpAddr>().ok()?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= max_prefix)?,
+            None => max_prefix,
+        };
+
+        Some(IpRange { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (range, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                (u32::from(range) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => (u128::from(range), u128::from(ip), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix;
+
+        range.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl std::fmt::Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// Coarse network prefix of an address (/24 for IPv4, /48 for IPv6), shown instead of full IPs
+pub fn get_ip_prefix(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(v4)) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{a}.{b}.{c}.0/24")
+        }
+        Ok(IpAddr::V6(v6)) => {
+            let [a, b, c, ..] = v6.segments();
+            format!("{a:x}:{b:x}:{c:x}::/48")
+        }
+        Err(_) => "unknown".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn hops() {
+        assert_eq!(parse_hop("203.0.113.7"), "203.0.113.7".parse().ok());
+        assert_eq!(parse_hop(" 203.0.113.7:4711 "), "203.0.113.7".parse().ok());
+        assert_eq!(
+            parse_hop("\"[2001:db8::1]:4711\""),
+            "2001:db8::1".parse().ok()
+        );
+        assert_eq!(parse_hop("2001:db8::1"), "2001:db8::1".parse().ok());
+        assert_eq!(parse_hop("unknown"), None);
+        assert_eq!(parse_hop("_hidden"), None);
+        assert_eq!(parse_hop("not an address"), None);
+        assert_eq!(parse_hop(""), None);
+    }
+
+    #[test]
+    fn client_ip_from_trusted_proxies() {
+        let trusted = [
+            IpRange::parse("127.0.0.0/8").unwrap(),
+            IpRange::parse("10.0.0.0/8").unwrap(),
+        ];
+        let proxy: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        // The last hop that is not a trusted proxy is the client
+        let xff = headers(&[("x-forwarded-for", "198.51.100.1, 203.0.113.7, 10.0.0.2")]);
+        assert_eq!(client_ip(&xff, proxy, &trusted), "203.0.113.7");
+
+        // Forwarded takes precedence over X-Forwarded-For
+        let forwarded = headers(&[
+            (
+                "forwarded",
+                "for=198.51.100.1;proto=https, for=\"[2001:db8::1]:4711\"",
+            ),
+            ("x-forwarded-for", "203.0.113.7"),
+        ]);
+        assert_eq!(client_ip(&forwarded, proxy, &trusted), "2001:db8::1");
+
+        // Unknown hops stop at the proxy that reported them
+        let unknown = headers(&[("forwarded", "for=203.0.113.7, for=unknown, for=10.0.0.2")]);
+        assert_eq!(client_ip(&unknown, proxy, &trusted), "10.0.0.2");
+
+        let garbage = headers(&[("x-forwarded-for", "garbage")]);
+        assert_eq!(client_ip(&garbage, proxy, &trusted), "127.0.0.1");
+        assert_eq!(client_ip(&HeaderMap::new(), proxy, &trusted), "127.0.0.1");
+
+        // IPv4 peers mapped to IPv6 are still recognised as trusted
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:5000".parse().unwrap();
+        assert_eq!(client_ip(&xff, mapped, &trusted), "203.0.113.7");
+    }
+
+    #[test]
+    fn client_ip_from_untrusted_peers() {
+        let trusted = [IpRange::parse("127.0.0.0/8").unwrap()];
+        let peer: SocketAddr = "198.51.100.9:5000".parse().unwrap();
+
+        let spoofed = headers(&[
+            ("x-forwarded-for", "203.0.113.7"),
+            ("forwarded", "for=203.0.113.7"),
+        ]);
+        assert_eq!(client_ip(&spoofed, peer, &trusted), "198.51.100.9");
+
+        let proxy: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        assert_eq!(client_ip(&spoofed, proxy, &[]), "127.0.0.1");
+    }
+
+    #[test]
+    fn email_normalisation() {
+        assert_eq!(
+            normalise_email("  Alice@Example.COM \n"),
+            Some("alice@example.com".to_owned())
+        );
+        assert_eq!(
+            normalise_email("alice.smith+tag@mail.example.com"),
+            Some("alice.smith+tag@mail.example.com".to_owned())
+        );
+        assert_eq!(
+            normalise_email("ÉMILE@EXAMPLE.COM"),
+            Some("émile@example.com".to_owned())
+        );
+
+        for invalid in [
+            "",
+            "   ",
+            "alice",
+            "@example.com",
+            "alice@",
+            "alice@example",
+            "alice@.example.com",
+            "alice@example.com.",
+            "alice@bob@example.com",
+            "alice smith@example.com",
+        ] {
+            assert_eq!(normalise_email(invalid), None, "{invalid}");
+        }
+
+        let long = format!("{}@example.com", "a".repeat(250));
+        assert_eq!(normalise_email(&long), None);
+    }
+
+    #[test]
+    fn like_escaping() {
+        assert_eq!(escape_like("a_b.com"), "a\\_b.com");
+        assert_eq!(escape_like("50%"), "50\\%");
+        assert_eq!(escape_like("a\\b"), "a\\\\b");
+        assert_eq!(escape_like("plain"), "plain");
+    }
+
+    #[test]
+    fn device_family() {
+        let firefox = |version| {
+            format!(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:{version}.0) Gecko/20100101 Firefox/{version}.0"
+            )
+        };
+        assert_eq!(get_device_family(&firefox(120)), "Firefox on Windows");
+        assert_eq!(
+            get_device_family(&firefox(120)),
+            get_device_family(&firefox(121))
+        );
+
+        assert_eq!(
+            get_device_family(
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Mobile/15E148 Safari/604.1"
+            ),
+            "Safari on iOS"
+        );
+        assert_eq!(
+            get_device_family(
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36"
+            ),
+            "Chrome on Android"
+        );
+        assert_eq!(
+            get_device_family(
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0"
+            ),
+            "Edge on macOS"
+        );
+        assert_eq!(get_device_family("curl/8.5.0"), "curl");
+        assert_eq!(get_device_family(""), "");
+    }
+
+    #[test]
+    fn hash_checking() {
+        let hash = generate_hash("42", "key");
+        assert!(check_hash("42", &hash, "key"));
+        assert!(!check_hash("43", &hash, "key"));
+        assert!(!check_hash("42", &hash, "other"));
+        assert!(!check_hash("42", &hash[..hash.len() - 2], "key"));
+        assert!(!check_hash("42", "not hex", "key"));
+        assert!(!check_hash("42", "", "key"));
+    }
+}
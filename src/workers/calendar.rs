@@ -18,7 +18,7 @@ use icalendar::{Calendar, CalendarComponent, Component, DatePerhapsTime, EventSt
 use std::{env, time::Duration};
 use tokio::time::interval;
 
-use crate::constants::{CALENDAR_DATETIME_FORMAT, DEFAULT_TZ};
+use crate::constants::{CALENDAR_DATETIME_FORMAT, DEFAULT_TZ, SLEEP_HOURS};
 use crate::state::{AppState, CalendarCache};
 
 const ZERO_TIME: NaiveTime = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
@@ -74,30 +74,8 @@ async fn get_busy_status(url: &str) -> anyhow::Result<(bool, DateTime<Utc>)> {
         }
     }
 
-    let get_time = |offset_days: i64, hour: u32| {
-        (now + chrono::Duration::days(offset_days))
-            .date_naive()
-            .and_hms_opt(hour, 0, 0)
-            .unwrap()
-            .and_local_timezone(*DEFAULT_TZ)
-            .unwrap()
-            .to_utc()
-    };
-
-    // Nighttime configuration - configured at 23.00-08.00 in user-configured timezone
-    const START_HOUR: u32 = 23;
-    const END_HOUR: u32 = 8;
-
-    // Add two blocking periods: Yesterday 23.00 to today 08.00, and today 23.00 to tomorrow 08.00
-    let yesterday_start = get_time(-1, START_HOUR);
-    let today_end = get_time(0, END_HOUR);
-    let today_start = get_time(0, START_HOUR);
-    let tomorrow_end = get_time(1, END_HOUR);
-
-    if today_end >= now {
-        blocking_datetimes.push((yesterday_start, today_end));
-    }
-    blocking_datetimes.push((today_start, tomorrow_end));
+    // Sleep hours block messages like calendar events do
+    blocking_datetimes.extend(SLEEP_HOURS.periods_around(now));
     blocking_datetimes.sort();
 
     let mut is_busy = false;
@@ -142,6 +120,7 @@ pub async fn calendar_worker(state: AppState) {
                 let new_cache = CalendarCache {
                     is_busy: busy_status.0,
                     timestamp: busy_status.1.format(CALENDAR_DATETIME_FORMAT).to_string(),
+                    busy_until: busy_status.0.then_some(busy_status.1),
                 };
 
                 {
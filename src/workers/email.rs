@@ -17,10 +17,12 @@ use askama::Template;
 use std::{collections::HashMap, time::Duration};
 use tokio::{task, time::sleep};
 
+use crate::blocklist::block_sender_link;
 use crate::constants::{
-    CARGO_PKG_VERSION, EMAIL_DATETIME_FORMAT, FROM_IMMEDIATE, FROM_STANDARD, FROM_URGENT,
-    NOTIFICATION_EMAIL,
+    CARGO_PKG_VERSION, EMAIL_DATETIME_FORMAT, ESCALATE_IMMEDIATE_BEFORE, ESCALATE_URGENT_BEFORE,
+    FROM_IMMEDIATE, FROM_STANDARD, FROM_URGENT, GUEST_PRIORITIES, NOTIFICATION_EMAIL,
 };
+use crate::roles::budget_period;
 use crate::state::AppState;
 use crate::utils::{capitalize_first, escape_html, send_email};
 
@@ -37,6 +39,7 @@ struct NotificationEmailTemplate<'a> {
     version: &'a str,
     sender_ip: &'a str,
     sender_ua: &'a str,
+    block_link: &'a str,
 }
 
 #[derive(Template)]
@@ -48,6 +51,17 @@ struct UserEmailTemplate<'a> {
     version: &'a str,
 }
 
+#[derive(Template)]
+#[template(path = "email_expired.html")]
+struct ExpiredEmailTemplate<'a> {
+    message: &'a str,
+    name: &'a str,
+    email: &'a str,
+    submitted_time: &'a str,
+    expired_time: &'a str,
+    version: &'a str,
+}
+
 pub async fn email_worker(state: AppState) {
     // SMTP_FROM(s) are emails where all the emails are sent from
     // This can be different from SMTP_USERNAME
@@ -61,17 +75,136 @@ pub async fn email_worker(state: AppState) {
     from_map.insert("immediate".to_string(), from_immediate);
 
     loop {
-        let messages = sqlx::query!("SELECT id, name, email, message, priority, sender, submitted_time, ua, ip FROM messages WHERE status = 'pending'")
-            .fetch_all(&state.db)
+        // Expire undelivered messages first, so that they are never dispatched late
+        let expired = sqlx::query!(
+            "UPDATE messages SET status = 'expired' WHERE status IN ('scheduled', 'pending') AND expires_at <= now() RETURNING name, email, message, priority, submitted_time, expires_at"
+        )
+        .fetch_all(&state.db)
+        .await
+        .unwrap();
+
+        for msg in expired {
+            let from = from_map
+                .get(msg.priority.as_str())
+                .cloned()
+                .expect("Priority must be one of the three options");
+            let priority_capitalised = capitalize_first(msg.priority);
+            let submitted_time = msg.submitted_time.format(EMAIL_DATETIME_FORMAT).to_string();
+            let expired_time = msg
+                .expires_at
+                .expect("Expired messages must have an expiry time")
+                .format(EMAIL_DATETIME_FORMAT)
+                .to_string();
+            let message_content = escape_html(msg.message);
+
+            let expired_subject = format!("[Enviame] {priority_capitalised} Message Expired");
+            let expired_template = ExpiredEmailTemplate {
+                message: &message_content,
+                name: &msg.name,
+                email: &msg.email,
+                submitted_time: &submitted_time,
+                expired_time: &expired_time,
+                version: CARGO_PKG_VERSION,
+            };
+            let expired_body = expired_template
+                .render()
+                .expect("Expired email failed to render");
+
+            task::spawn(async move {
+                let expired_result = send_email(
+                    from,
+                    &msg.email,
+                    &NOTIFICATION_EMAIL,
+                    &expired_subject,
+                    &expired_body,
+                )
+                .await;
+
+                if let Err(ref err) = expired_result {
+                    eprintln!("Email worker failed to send expiry notice: {err:?}");
+                }
+            });
+        }
+
+        // Guest messages that were never confirmed expire along with their code
+        sqlx::query!(
+            "WITH stale AS (DELETE FROM guest_verifications WHERE expires_time <= now() RETURNING message_id) UPDATE messages SET status = 'expired' WHERE id IN (SELECT message_id FROM stale) AND status = 'unconfirmed'"
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        // Escalate undelivered messages as their deadline approaches, recording each change.
+        //    Immediate is checked first so that a message skipping urgent is logged once,
+        //    and messages only escalate to priorities their sender is allowed to send,
+        //    the guest priorities for messages without a sender account.
+        //    Escalations count against weekly budgets, those with the earliest deadline first,
+        //    and their senders are locked like during submissions so budgets cannot be overspent
+        let (period_start, _) = budget_period();
+        let mut tx = state.db.begin().await.unwrap();
+        sqlx::query!(
+            "SELECT uid FROM users WHERE uid IN (SELECT user_uid FROM messages WHERE status IN ('scheduled', 'pending') AND priority <> 'immediate' AND needed_by <= $1) ORDER BY uid FOR UPDATE",
+            chrono::Utc::now() + ESCALATE_URGENT_BEFORE
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        for (new_priority, escalate_before) in [
+            ("immediate", ESCALATE_IMMEDIATE_BEFORE),
+            ("urgent", ESCALATE_URGENT_BEFORE),
+        ] {
+            sqlx::query!(
+                "WITH candidates AS (SELECT m.id, m.user_uid, m.priority AS old_priority, CASE $1 WHEN 'urgent' THEN r.weekly_urgent_budget WHEN 'immediate' THEN r.weekly_immediate_budget END AS budget, ROW_NUMBER() OVER (PARTITION BY m.user_uid ORDER BY m.needed_by, m.id) AS n FROM messages m LEFT JOIN users u ON u.uid = m.user_uid LEFT JOIN roles r ON r.id = u.role WHERE m.status IN ('scheduled', 'pending') AND m.priority <> 'immediate' AND m.priority <> $1 AND m.needed_by <= $2 AND (CASE WHEN m.user_uid IS NULL THEN $1 = ANY($3) ELSE $1 = ANY(COALESCE(u.allowed_priorities, r.allowed_priorities)) END)), escalated AS (UPDATE messages m SET priority = $1 FROM candidates c WHERE m.id = c.id AND (c.budget IS NULL OR c.n <= c.budget - (SELECT COUNT(*) FROM messages b WHERE b.user_uid = c.user_uid AND (b.submitted_priority = $1 OR b.priority = $1) AND b.status <> 'cancelled' AND b.submitted_time >= $4)) RETURNING m.id, c.old_priority) INSERT INTO delivery_log (message_id, event, detail) SELECT id, 'escalated', old_priority || ' -> ' || $1 FROM escalated",
+                new_priority,
+                chrono::Utc::now() + escalate_before,
+                &GUEST_PRIORITIES as &[&str],
+                period_start
+            )
+            .execute(&mut *tx)
             .await
             .unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        // Release scheduled messages whose delivery time has come
+        sqlx::query!(
+            "UPDATE messages SET status = 'pending' WHERE status = 'scheduled' AND deliver_after <= now()"
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        // Standard messages are held until the end of the current busy period,
+        //    unless their sender's role bypasses sleep
+        let is_holding_standard = state.status.read().await.held_until("standard").is_some();
+
+        let messages = sqlx::query!(
+            "SELECT id FROM messages m WHERE status = 'pending' AND (priority <> 'standard' OR NOT $1 OR EXISTS (SELECT 1 FROM users u JOIN roles r ON r.id = u.role WHERE u.uid = m.user_uid AND r.bypass_sleep)) ORDER BY id",
+            is_holding_standard
+        )
+        .fetch_all(&state.db)
+        .await
+        .unwrap();
 
         if messages.is_empty() {
             sleep(Duration::from_secs(10)).await;
             continue;
         }
 
-        for msg in messages {
+        for pending in messages {
+            // Claim the message right before dispatching it, skipping it if it was
+            //    cancelled, edited to be held or expired since the batch was fetched
+            let Some(msg) = sqlx::query!(
+                "UPDATE messages m SET status = 'sending' WHERE id = $1 AND status = 'pending' AND (priority <> 'standard' OR NOT $2 OR EXISTS (SELECT 1 FROM users u JOIN roles r ON r.id = u.role WHERE u.uid = m.user_uid AND r.bypass_sleep)) AND (expires_at IS NULL OR expires_at > now()) RETURNING id, name, email, message, priority, sender, submitted_time, ua, ip",
+                pending.id,
+                is_holding_standard
+            )
+            .fetch_optional(&state.db)
+            .await
+            .unwrap() else {
+                continue;
+            };
+
             // Clone state for new thread
             let state = state.clone();
 
@@ -92,6 +225,7 @@ pub async fn email_worker(state: AppState) {
                 "[Enviame] {} Message from {}({})",
                 priority_capitalised, msg.name, sender_type_capitalised
             );
+            let block_link = block_sender_link(msg.id);
             let notification_template = NotificationEmailTemplate {
                 message: &message_content,
                 priority: &priority_capitalised,
@@ -103,6 +237,7 @@ pub async fn email_worker(state: AppState) {
                 version: CARGO_PKG_VERSION,
                 sender_ip: &msg.ip,
                 sender_ua: &msg.ua,
+                block_link: &block_link,
             };
             let notification_body = notification_template
                 .render()
@@ -119,14 +254,6 @@ pub async fn email_worker(state: AppState) {
 
             // Send email in new thread
             task::spawn(async move {
-                sqlx::query!(
-                    "UPDATE messages SET status = 'sending' WHERE id = $1",
-                    msg.id
-                )
-                .execute(&state.db)
-                .await
-                .unwrap();
-
                 let mut is_ok = true;
 
                 let notification_result = send_email(